@@ -0,0 +1,591 @@
+//! A parse tree for Lua patterns, for tools which need to look inside them.
+//!
+//! ```
+//! use lua_patterns2::LuaPattern;
+//! use lua_patterns2::ast::{Item, Class, Quantifier};
+//!
+//! let items = LuaPattern::new("^(%d+)x").parse();
+//! assert_eq!(items, &[
+//!     Item::Start,
+//!     Item::Capture{index: 1, items: vec![
+//!         Item::Quantified{
+//!             item: Box::new(Item::Class{class: Class::Digit, negated: false}),
+//!             quantifier: Quantifier::OneOrMore
+//!         }
+//!     ]},
+//!     Item::Literal(b'x'),
+//! ]);
+//! ```
+
+use std::boxed::Box;
+use std::vec::Vec;
+
+use crate::errors::PatternError;
+use crate::info::{self, Items};
+use crate::luapat::str_check;
+use crate::LuaPattern;
+
+/// The character classes written as `%a`, `%d` and so on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Class {
+	/// `%a`, letters
+	Letter,
+	/// `%c`, control characters
+	Control,
+	/// `%d`, digits
+	Digit,
+	/// `%g`, printable characters except space
+	Graphic,
+	/// `%l`, lowercase letters
+	Lower,
+	/// `%p`, punctuation
+	Punct,
+	/// `%s`, whitespace
+	Space,
+	/// `%u`, uppercase letters
+	Upper,
+	/// `%w`, letters and digits
+	AlphaNum,
+	/// `%x`, hexadecimal digits
+	HexDigit,
+}
+
+impl Class {
+	/// The class for a letter following `%`, and whether it is negated (uppercase)
+	///
+	/// ```
+	/// use lua_patterns2::ast::Class;
+	/// assert_eq!(Class::from_byte(b'd'), Some((Class::Digit, false)));
+	/// assert_eq!(Class::from_byte(b'S'), Some((Class::Space, true)));
+	/// assert_eq!(Class::from_byte(b'y'), None);
+	/// ```
+	pub fn from_byte(b: u8) -> Option<(Class, bool)> {
+		let class = match b.to_ascii_lowercase() {
+			b'a' => Class::Letter,
+			b'c' => Class::Control,
+			b'd' => Class::Digit,
+			b'g' => Class::Graphic,
+			b'l' => Class::Lower,
+			b'p' => Class::Punct,
+			b's' => Class::Space,
+			b'u' => Class::Upper,
+			b'w' => Class::AlphaNum,
+			b'x' => Class::HexDigit,
+			_ => return None
+		};
+		Some((class, b.is_ascii_uppercase()))
+	}
+
+	/// The letter used for this class after `%`, uppercase if negated
+	pub fn to_byte(self, negated: bool) -> u8 {
+		let b = match self {
+			Class::Letter => b'a',
+			Class::Control => b'c',
+			Class::Digit => b'd',
+			Class::Graphic => b'g',
+			Class::Lower => b'l',
+			Class::Punct => b'p',
+			Class::Space => b's',
+			Class::Upper => b'u',
+			Class::AlphaNum => b'w',
+			Class::HexDigit => b'x',
+		};
+		if negated {b.to_ascii_uppercase()} else {b}
+	}
+}
+
+/// The suffixes which repeat a single character item
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Quantifier {
+	/// `*`, as many as possible
+	ZeroOrMore,
+	/// `+`, at least one, as many as possible
+	OneOrMore,
+	/// `-`, as few as possible
+	Lazy,
+	/// `?`, zero or one
+	Optional,
+}
+
+impl Quantifier {
+	/// The suffix for a quantifier character, if it is one
+	pub fn from_byte(b: u8) -> Option<Quantifier> {
+		match b {
+			b'*' => Some(Quantifier::ZeroOrMore),
+			b'+' => Some(Quantifier::OneOrMore),
+			b'-' => Some(Quantifier::Lazy),
+			b'?' => Some(Quantifier::Optional),
+			_ => None
+		}
+	}
+
+	/// The character written for this quantifier
+	pub fn to_byte(self) -> u8 {
+		match self {
+			Quantifier::ZeroOrMore => b'*',
+			Quantifier::OneOrMore => b'+',
+			Quantifier::Lazy => b'-',
+			Quantifier::Optional => b'?',
+		}
+	}
+}
+
+/// A bracketed set like `[^%a_]`, as used on its own or by `%f`
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Set {
+	/// Starts with `^`, so matches bytes _not_ in the set
+	pub negated: bool,
+	/// Inclusive byte ranges; single bytes are ranges of one
+	pub ranges: Vec<(u8, u8)>,
+	/// Classes like `%s` in the set, with whether each is negated
+	pub classes: Vec<(Class, bool)>,
+}
+
+impl Set {
+	/// Add a byte to the set
+	pub fn byte(mut self, b: u8) -> Set {
+		self.ranges.push((b, b));
+		self
+	}
+
+	/// Add the bytes from `lo` to `hi` inclusive to the set
+	///
+	/// ```
+	/// use lua_patterns2::ast::{print, Class, Item, Set};
+	/// let set = Set::default().range(b'a', b'f').class(Class::Space).byte(b']');
+	/// assert_eq!(print(&[Item::Set(set)]), b"[%sa-f%]]");
+	/// ```
+	pub fn range(mut self, lo: u8, hi: u8) -> Set {
+		self.ranges.push((lo, hi));
+		self
+	}
+
+	/// Add a class like `%s` to the set
+	pub fn class(mut self, class: Class) -> Set {
+		self.classes.push((class, false));
+		self
+	}
+
+	/// Add a negated class like `%S` to the set
+	pub fn not_class(mut self, class: Class) -> Set {
+		self.classes.push((class, true));
+		self
+	}
+
+	/// Match the bytes _not_ in the set
+	pub fn negate(mut self) -> Set {
+		self.negated = ! self.negated;
+		self
+	}
+}
+
+/// One element of a Lua pattern
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Item {
+	/// `^` at the start of the pattern
+	Start,
+	/// `$` at the end of the pattern
+	End,
+	/// A byte matching only itself, maybe escaped with `%`
+	Literal(u8),
+	/// `.`, any byte
+	Any,
+	/// A class like `%d`, or `%D` when negated
+	Class {
+		class: Class,
+		negated: bool
+	},
+	/// A bracketed set
+	Set(Set),
+	/// A single character item with a suffix
+	Quantified {
+		item: Box<Item>,
+		quantifier: Quantifier
+	},
+	/// `(...)`, numbered from 1 in order of the opening parens
+	Capture {
+		index: usize,
+		items: Vec<Item>
+	},
+	/// `()`, capturing the position; numbered like other captures
+	PositionCapture {
+		index: usize
+	},
+	/// `%bxy`, text balanced between `x` and `y`
+	Balance {
+		open: u8,
+		close: u8
+	},
+	/// `%f[set]`, the boundary where the previous byte is not in the
+	/// set and the next byte is
+	Frontier(Set),
+	/// `%1` to `%9`, matching a previous capture again
+	BackRef(BackRefIndex),
+}
+
+/// The capture a back reference matches again, from 1 to 9
+///
+/// ```
+/// use lua_patterns2::ast::{print, BackRefIndex, Item};
+/// let second = BackRefIndex::new(2).unwrap();
+/// assert_eq!(second.get(), 2);
+/// assert_eq!(print(&[Item::BackRef(second)]), b"%2");
+/// assert!(BackRefIndex::new(10).is_none());
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BackRefIndex(u8);
+
+impl BackRefIndex {
+	/// The index, if it is in `1..=9`
+	pub fn new(index: usize) -> Option<BackRefIndex> {
+		if (1..=9).contains(&index) {Some(BackRefIndex(index as u8))} else {None}
+	}
+
+	/// The index as a number
+	pub fn get(self) -> usize {
+		self.0 as usize
+	}
+}
+
+fn parse_set(set: &[u8]) -> Set {
+	// set is "[...]", including both brackets
+	let end = set.len() - 1;
+	let mut res = Set::default();
+	let mut j = 1;
+	if set[j] == b'^' {
+		res.negated = true;
+		j += 1;
+	}
+	// as in matchbracketclass
+	while j < end {
+		if set[j] == b'%' {
+			j += 1;
+			match Class::from_byte(set[j]) {
+				Some(class) => res.classes.push(class),
+				None => res.ranges.push((set[j], set[j]))
+			}
+		} else if set[j + 1] == b'-' && j + 2 < end {
+			res.ranges.push((set[j], set[j + 2]));
+			j += 2;
+		} else {
+			res.ranges.push((set[j], set[j]));
+		}
+		j += 1;
+	}
+	res
+}
+
+fn parse_single(class: &[u8]) -> Item {
+	match class[0] {
+		b'.' => Item::Any,
+		b'[' => Item::Set(parse_set(class)),
+		b'%' => match Class::from_byte(class[1]) {
+			Some((class, negated)) => Item::Class{class, negated},
+			None => Item::Literal(class[1])
+		},
+		c => Item::Literal(c)
+	}
+}
+
+// build the tree for a pattern which has passed str_check
+pub(crate) fn parse_checked(p: &[u8]) -> Vec<Item> {
+	let mut items = Vec::new();
+	if p.first() == Some(&b'^') {
+		items.push(Item::Start);
+	}
+	// enclosing items and capture index, for each open capture
+	let mut open: Vec<(Vec<Item>, usize)> = Vec::new();
+	let mut level = 0;
+	for item in Items::new(p) {
+		let parsed = match item {
+			info::Item::Open => {
+				level += 1;
+				open.push((core::mem::take(&mut items), level));
+				continue;
+			},
+			info::Item::Close => {
+				let (outer, index) = open.pop().expect("checked pattern");
+				let inner = core::mem::replace(&mut items, outer);
+				Item::Capture{index, items: inner}
+			},
+			info::Item::Position => {
+				level += 1;
+				Item::PositionCapture{index: level}
+			},
+			info::Item::End => Item::End,
+			info::Item::Balance(open, close) => Item::Balance{open, close},
+			info::Item::Frontier(set) => Item::Frontier(parse_set(set)),
+			info::Item::BackRef(l) => Item::BackRef(BackRefIndex(l as u8 + 1)),
+			info::Item::Single(class, suffix) => {
+				let single = parse_single(class);
+				match suffix.and_then(Quantifier::from_byte) {
+					Some(quantifier) => Item::Quantified{item: Box::new(single), quantifier},
+					None => single
+				}
+			}
+		};
+		items.push(parsed);
+	}
+	items
+}
+
+/// Parse a pattern into its items
+///
+/// ```
+/// use lua_patterns2::ast::{parse, Item, Set, Class};
+/// assert_eq!(parse(b"%f[%w_]%b()").unwrap(), &[
+///     Item::Frontier(Set{negated: false, ranges: vec![(b'_', b'_')], classes: vec![(Class::AlphaNum, false)]}),
+///     Item::Balance{open: b'(', close: b')'},
+/// ]);
+/// assert!(parse(b"(oops").is_err());
+/// ```
+pub fn parse(patt: &[u8]) -> Result<Vec<Item>, PatternError> {
+	str_check(patt)?;
+	Ok(parse_checked(patt))
+}
+
+// bytes which can't start a range in a set as they are
+fn range_start_magic(c: u8, first: bool, plain: bool) -> bool {
+	match c {
+		b'%' | b']' => true,
+		b'^' => first,
+		// "x--z" would be the range x to -
+		b'-' => plain,
+		_ => false
+	}
+}
+
+fn push_escaped(c: u8, escape: bool, out: &mut Vec<u8>) {
+	if escape {
+		out.push(b'%');
+	}
+	out.push(c);
+}
+
+// where a set starting with '[' ends, as the matcher finds it
+fn set_len(set: &[u8]) -> Option<usize> {
+	let mut i = 1;
+	if set.get(i) == Some(&b'^') {
+		i += 1;
+	}
+	loop {
+		// the byte after a '%' never ends the set
+		i += if *set.get(i)? == b'%' {2} else {1};
+		if *set.get(i)? == b']' {
+			return Some(i + 1);
+		}
+	}
+}
+
+// the set with each range written as it is, which may not read back the same
+fn print_set_as_is(set: &Set, classes_last: bool) -> Vec<u8> {
+	let mut out = Vec::from(if set.negated {&b"[^"[..]} else {&b"["[..]});
+	let start = out.len();
+	let classes = |out: &mut Vec<u8>| {
+		for &(class, negated) in &set.classes {
+			out.extend_from_slice(&[b'%', class.to_byte(negated)]);
+		}
+	};
+	if ! classes_last {
+		classes(&mut out);
+	}
+	// the byte after a '%' is skipped looking for the end of the set, so
+	// after a range ending with '%' a ']' can be written as it is
+	let mut skip = false;
+	for (i, &(lo, hi)) in set.ranges.iter().enumerate() {
+		let first = out.len() == start;
+		let before = out.len();
+		if lo == hi {
+			// "x--z" would be the range x to -
+			let before_dash = matches!(set.ranges.get(i + 1), Some(&(b'-', h)) if h != b'-');
+			let escape = matches!(lo, b'%' | b'-') || (lo == b']' && ! skip)
+				|| (lo == b'^' && first && ! set.negated) || before_dash;
+			if escape && Class::from_byte(lo).is_some() {
+				// "%a" would be a class, but "a-a" is the same byte
+				out.extend_from_slice(&[lo, b'-', lo]);
+			} else {
+				push_escaped(lo, escape, &mut out);
+			}
+		} else {
+			out.extend_from_slice(&[lo, b'-', hi]);
+		}
+		for &c in &out[before..] {
+			skip = ! skip && c == b'%';
+		}
+	}
+	if classes_last {
+		classes(&mut out);
+	}
+	out.push(b']');
+	out
+}
+
+fn print_set(set: &Set, out: &mut Vec<u8>) {
+	// as it is if that reads back the same, with the classes first or
+	// last, which matters after a range ending with '%'
+	for classes_last in [false, true] {
+		let text = print_set_as_is(set, classes_last);
+		if set_len(&text) == Some(text.len()) && parse_set(&text) == *set {
+			out.extend(text);
+			return;
+		}
+	}
+	print_set_split(set, out);
+}
+
+// the set with ranges split up so that they can be written, or left out
+// if they are empty
+fn print_set_split(set: &Set, out: &mut Vec<u8>) {
+	out.push(b'[');
+	if set.negated {
+		out.push(b'^');
+	}
+	let start = out.len();
+	// a '^' here would negate the set
+	let at_start = |out: &Vec<u8>| ! set.negated && out.len() == start;
+	for &(class, negated) in &set.classes {
+		out.extend_from_slice(&[b'%', class.to_byte(negated)]);
+	}
+	// the last byte written was a lone unescaped byte
+	let mut plain = false;
+	for (i, &(lo, hi)) in set.ranges.iter().enumerate() {
+		let first = at_start(out);
+		if lo == hi {
+			let magic = matches!(lo, b'%' | b']' | b'-') || (lo == b'^' && first);
+			let before_dash = matches!(set.ranges.get(i + 1), Some(&(b'-', h)) if h != b'-');
+			if before_dash && Class::from_byte(lo).is_some() {
+				// "%a" would be a class, but "a-a" stops the '-' joining it
+				out.extend_from_slice(&[lo, b'-', lo]);
+				plain = false;
+			} else {
+				let escape = magic || before_dash;
+				push_escaped(lo, escape, out);
+				plain = ! escape;
+			}
+			continue;
+		}
+		if lo > hi {
+			// matches nothing, so leave it out if it can't be written
+			if ! range_start_magic(lo, first, plain) && ! matches!(hi, b'%' | b']') {
+				out.extend_from_slice(&[lo, b'-', hi]);
+				plain = false;
+			}
+			continue;
+		}
+		let (mut lo, mut hi) = (lo, hi);
+		while lo < hi && range_start_magic(lo, at_start(out), plain) {
+			push_escaped(lo, true, out);
+			plain = false;
+			lo += 1;
+		}
+		let top = hi;
+		while lo < hi && matches!(hi, b'%' | b']') {
+			hi -= 1;
+		}
+		if lo == hi {
+			let magic = matches!(lo, b'%' | b']' | b'-') || (lo == b'^' && at_start(out));
+			push_escaped(lo, magic, out);
+		} else {
+			out.extend_from_slice(&[lo, b'-', hi]);
+		}
+		plain = false;
+		for c in hi..top {
+			push_escaped(c + 1, true, out);
+		}
+	}
+	out.push(b']');
+}
+
+// a single character item; `first` if it starts the pattern and
+// `after_single` if a quantifier here would apply to the item before
+fn print_single(item: &Item, first: bool, after_single: bool, out: &mut Vec<u8>) {
+	match *item {
+		Item::Literal(c) => {
+			let magic = match c {
+				b'%' | b'(' | b')' | b'.' | b'[' => true,
+				b'*' | b'+' | b'-' | b'?' => after_single,
+				b'^' => first,
+				_ => false
+			};
+			push_escaped(c, magic, out);
+		},
+		Item::Any => out.push(b'.'),
+		Item::Class{class, negated} => out.extend_from_slice(&[b'%', class.to_byte(negated)]),
+		Item::Set(ref set) => print_set(set, out),
+		ref other => print_items(core::slice::from_ref(other), false, out)
+	}
+}
+
+fn print_items(items: &[Item], top: bool, out: &mut Vec<u8>) {
+	let mut after_single = false;
+	for (i, item) in items.iter().enumerate() {
+		let first = top && i == 0;
+		match *item {
+			Item::Start => out.push(b'^'),
+			Item::End => out.push(b'$'),
+			Item::Literal(b'$') if top && i + 1 == items.len() => out.extend_from_slice(b"%$"),
+			Item::Quantified{ref item, quantifier} => {
+				print_single(item, first, after_single, out);
+				out.push(quantifier.to_byte());
+			},
+			Item::Capture{ref items, ..} => {
+				out.push(b'(');
+				print_items(items, false, out);
+				out.push(b')');
+			},
+			Item::PositionCapture{..} => out.extend_from_slice(b"()"),
+			Item::Balance{open, close} => out.extend_from_slice(&[b'%', b'b', open, close]),
+			Item::Frontier(ref set) => {
+				out.extend_from_slice(b"%f");
+				print_set(set, out);
+			},
+			Item::BackRef(l) => out.extend_from_slice(&[b'%', b'0' + l.0]),
+			ref single => print_single(single, first, after_single, out)
+		}
+		after_single = matches!(*item, Item::Literal(_) | Item::Any | Item::Class{..} | Item::Set(_));
+	}
+}
+
+/// Write items back as pattern text
+///
+/// Punctuation is only escaped where it would otherwise be magic, and
+/// `parse` of the result gives back the same items. Capture indices are
+/// ignored, since captures are numbered by where they are. `Start` and `End`
+/// are only meaningful first and last, and an empty `Capture` reads back as
+/// a `PositionCapture`, as there is no other way to write it. A set which
+/// can't be written to read back the same, like `!` to `%` on its own, has
+/// its ranges split up or left out if they are empty, so it reads back as
+/// other ranges matching the same bytes.
+///
+/// ```
+/// use lua_patterns2::ast::{parse, print, Item};
+/// let mut items = parse(b"^(%d+)%.(%d+)").unwrap();
+/// items.swap(1, 3);
+/// assert_eq!(print(&items), b"^(%d+)%.(%d+)");
+/// items.push(Item::Literal(b'$'));
+/// assert_eq!(print(&items), b"^(%d+)%.(%d+)%$");
+/// assert_eq!(print(&parse(b"[%a_%-]-%]").unwrap()), b"[%a_%-]-]");
+/// ```
+pub fn print(items: &[Item]) -> Vec<u8> {
+	let mut out = Vec::new();
+	print_items(items, true, &mut out);
+	out
+}
+
+impl <'a> LuaPattern<'a> {
+	/// The items of this pattern, as a tree
+	///
+	/// ```
+	/// use lua_patterns2::LuaPattern;
+	/// use lua_patterns2::ast::{Item, Set};
+	/// let items = LuaPattern::new("()[^a-f]$").parse();
+	/// assert_eq!(items, &[
+	///     Item::PositionCapture{index: 1},
+	///     Item::Set(Set{negated: true, ranges: vec![(b'a', b'f')], classes: vec![]}),
+	///     Item::End,
+	/// ]);
+	/// ```
+	pub fn parse(&self) -> Vec<Item> {
+		parse_checked(self.patt)
+	}
+}
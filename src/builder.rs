@@ -5,18 +5,67 @@ use std::format;
 use std::vec::Vec;
 use std::string::String;
 
+use crate::ast::{self, BackRefIndex, Class, Item, Quantifier, Set};
+use crate::errors::{BuildError, BuildErrorKind};
+use crate::luapat::str_check;
 use crate::LuaPattern;
 
+// where the set starting at p[i] ends, as str_check reads it
+fn set_end(p: &[u8], mut i: usize) -> usize {
+	if p.get(i) != Some(&b'[') {
+		return i;
+	}
+	i += 1;
+	if p.get(i) == Some(&b'^') {
+		i += 1;
+	}
+	while i < p.len() && p[i] != b']' {
+		if p[i] == b'%' {
+			i += 1;
+		}
+		i += 1;
+	}
+	(i + 1).min(p.len())
+}
+
+// the number of captures opened in a pattern, which may be unfinished
+fn count_captures(p: &[u8]) -> usize {
+	let mut n = 0;
+	let mut i = 0;
+	while i < p.len() {
+		i = match p[i] {
+			b'(' => {
+				n += 1;
+				i + 1
+			},
+			b'%' => match p.get(i + 1) {
+				Some(b'b') => i + 4,
+				Some(b'f') => set_end(p, i + 2),
+				_ => i + 2
+			},
+			b'[' => set_end(p, i),
+			_ => i + 1
+		};
+	}
+	n
+}
+
 /// Build a byte Lua pattern, optionally escaping 'magic' characters
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct LuaPatternBuilder {
-	bytes: Vec<u8>
+	bytes: Vec<u8>,
+	// the last thing added was a single character item, so can be repeated
+	single: bool,
+	// where each call adding to the pattern started
+	steps: Vec<usize>,
+	// the first call which could not add to the pattern
+	misuse: Option<BuildError>,
 }
 
 impl LuaPatternBuilder {
 	/// Create a new Lua pattern builder
 	pub fn new() -> LuaPatternBuilder {
-		LuaPatternBuilder{bytes: Vec::new()}
+		LuaPatternBuilder{bytes: Vec::new(), single: false, steps: Vec::new(), misuse: None}
 	}
 
 	/// Add unescaped characters from a string
@@ -28,7 +77,9 @@ impl LuaPatternBuilder {
 	/// assert_eq!(std::str::from_utf8(&patt).unwrap(), "(boo)");
 	/// ```
 	pub fn text(&mut self, s: &str) -> &mut Self {
+		self.steps.push(self.bytes.len());
 		self.bytes.extend_from_slice(s.as_bytes());
+		self.single = false;
 		self
 	}
 
@@ -76,7 +127,9 @@ impl LuaPatternBuilder {
 			res.push(cc.get(0)[0]);
 			res
 		});
+		self.steps.push(self.bytes.len());
 		self.bytes.extend(bb);
+		self.single |= ! b.is_empty();
 		self
 	}
 
@@ -96,13 +149,303 @@ impl LuaPatternBuilder {
 		self.bytes(&bb)
 	}
 
+	// a call which adds nothing, leaving the error for try_build
+	fn misuse(&mut self, step: usize, error: BuildErrorKind) -> &mut Self {
+		if self.misuse.is_none() {
+			self.misuse = Some(BuildError{step, error});
+		}
+		self.single = false;
+		self
+	}
+
+	fn push_misuse(&mut self, error: BuildErrorKind) -> &mut Self {
+		self.steps.push(self.bytes.len());
+		self.misuse(self.steps.len() - 1, error)
+	}
+
+	fn push_single(&mut self, item: Item) -> &mut Self {
+		self.steps.push(self.bytes.len());
+		self.bytes.extend(ast::print(&[item]));
+		self.single = true;
+		self
+	}
+
+	/// Add `.`, matching any byte
+	pub fn any(&mut self) -> &mut Self {
+		self.push_single(Item::Any)
+	}
+
+	/// Add a class like `%d`
+	///
+	/// ```
+	/// use lua_patterns2::ast::Class;
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .class(Class::Digit).one_or_more()
+	///     .bytes(b".")
+	///     .not_class(Class::Space).zero_or_more()
+	///     .build();
+	/// assert_eq!(patt, b"%d+%.%S*");
+	/// ```
+	pub fn class(&mut self, class: Class) -> &mut Self {
+		self.push_single(Item::Class{class, negated: false})
+	}
+
+	/// Add a negated class like `%D`
+	pub fn not_class(&mut self, class: Class) -> &mut Self {
+		self.push_single(Item::Class{class, negated: true})
+	}
+
+	/// Add a set like `[a-f%s]`, built up from an empty one
+	///
+	/// An empty set can't be written, so is an error from `try_build`.
+	///
+	/// ```
+	/// use lua_patterns2::ast::Class;
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .set(|s| s.range(b'a', b'f').class(Class::Space).byte(b']'))
+	///     .set(|s| s.byte(b'^').negate())
+	///     .build();
+	/// assert_eq!(patt, b"[%sa-f%]][^^]");
+	/// ```
+	pub fn set<F>(&mut self, f: F) -> &mut Self
+	where F: FnOnce(Set) -> Set {
+		let set = f(Set::default());
+		if set.ranges.is_empty() && set.classes.is_empty() {
+			return self.push_misuse(BuildErrorKind::EmptySet);
+		}
+		self.push_single(Item::Set(set))
+	}
+
+	fn quantify(&mut self, q: Quantifier) -> &mut Self {
+		if ! self.single {
+			return self.push_misuse(BuildErrorKind::NothingToRepeat);
+		}
+		self.steps.push(self.bytes.len());
+		self.bytes.push(q.to_byte());
+		self.single = false;
+		self
+	}
+
+	/// Repeat the last single character item zero or more times, as `*`
+	///
+	/// That is the last byte added by `bytes`, or the last `any`, `class`
+	/// or `set`. If there is none, or it is already repeated, this is an
+	/// error from `try_build`.
+	pub fn zero_or_more(&mut self) -> &mut Self {
+		self.quantify(Quantifier::ZeroOrMore)
+	}
+
+	/// Repeat the last single character item one or more times, as `+`
+	pub fn one_or_more(&mut self) -> &mut Self {
+		self.quantify(Quantifier::OneOrMore)
+	}
+
+	/// Repeat the last single character item as few times as possible, as `-`
+	///
+	/// ```
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .bytes(b"<").any().lazy().bytes(b">")
+	///     .build();
+	/// assert_eq!(patt, b"<.->");
+	/// ```
+	pub fn lazy(&mut self) -> &mut Self {
+		self.quantify(Quantifier::Lazy)
+	}
+
+	/// Make the last single character item optional, as `?`
+	pub fn optional(&mut self) -> &mut Self {
+		self.quantify(Quantifier::Optional)
+	}
+
+	/// Add a capture of whatever `f` adds
+	///
+	/// If `f` adds nothing this is an error from `try_build`, since `()` is
+	/// a position capture.
+	///
+	/// ```
+	/// use lua_patterns2::ast::Class;
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .capture(|b| b.class(Class::Letter).one_or_more())
+	///     .bytes(b"=")
+	///     .capture(|b| b.any().zero_or_more())
+	///     .build();
+	/// assert_eq!(patt, b"(%a+)=(.*)");
+	/// ```
+	pub fn capture<F>(&mut self, f: F) -> &mut Self
+	where F: FnOnce(&mut Self) -> &mut Self {
+		let step = self.steps.len();
+		self.steps.push(self.bytes.len());
+		self.bytes.push(b'(');
+		self.single = false;
+		let start = self.bytes.len();
+		f(self);
+		if self.bytes.len() == start {
+			self.misuse(step, BuildErrorKind::EmptyCapture);
+		}
+		self.bytes.push(b')');
+		self.single = false;
+		self
+	}
+
+	/// Add `()`, capturing the position
+	pub fn position(&mut self) -> &mut Self {
+		self.text("()")
+	}
+
+	/// Add `%bxy`, matching text balanced between `open` and `close`
+	///
+	/// ```
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .bytes(b"f").balanced(b'(', b')')
+	///     .build();
+	/// assert_eq!(patt, b"f%b()");
+	/// ```
+	pub fn balanced(&mut self, open: u8, close: u8) -> &mut Self {
+		self.steps.push(self.bytes.len());
+		self.bytes.extend_from_slice(&[b'%', b'b', open, close]);
+		self.single = false;
+		self
+	}
+
+	/// Add `%f[set]`, matching where the previous byte is not in the set
+	/// and the next one is
+	///
+	/// ```
+	/// use lua_patterns2::ast::{Class, Set};
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .frontier(Set::default().class(Class::Letter))
+	///     .bytes(b"THE")
+	///     .frontier(Set::default().class(Class::Letter).negate())
+	///     .build();
+	/// assert_eq!(patt, b"%f[%a]THE%f[^%a]");
+	/// ```
+	pub fn frontier(&mut self, set: Set) -> &mut Self {
+		if set.ranges.is_empty() && set.classes.is_empty() {
+			return self.push_misuse(BuildErrorKind::EmptySet);
+		}
+		self.steps.push(self.bytes.len());
+		self.bytes.extend(ast::print(&[Item::Frontier(set)]));
+		self.single = false;
+		self
+	}
+
+	/// Add `%1` to `%9`, matching the text of an earlier capture again
+	///
+	/// An `index` not in `1..=9` is an error from `try_build`.
+	///
+	/// ```
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .capture(|b| b.bytes(b"\"").any().lazy())
+	///     .backref(1)
+	///     .build();
+	/// assert_eq!(patt, b"(\".-)%1");
+	/// ```
+	pub fn backref(&mut self, index: usize) -> &mut Self {
+		let index = match BackRefIndex::new(index) {
+			Some(index) => index,
+			None => return self.push_misuse(BuildErrorKind::InvalidBackRef(index))
+		};
+		self.steps.push(self.bytes.len());
+		self.bytes.extend(ast::print(&[Item::BackRef(index)]));
+		self.single = false;
+		self
+	}
+
+	/// Anchor the pattern at the start of the subject, as `^`
+	///
+	/// If anything was added before, where `^` would be a literal, this is
+	/// an error from `try_build`.
+	pub fn start(&mut self) -> &mut Self {
+		if ! self.bytes.is_empty() {
+			return self.push_misuse(BuildErrorKind::MisplacedStart);
+		}
+		self.text("^")
+	}
+
+	/// Anchor the pattern at the end of the subject, as `$`
+	///
+	/// This only anchors if nothing is added after it.
+	///
+	/// ```
+	/// let patt = lua_patterns2::LuaPatternBuilder::new()
+	///     .start().bytes(b"$5").end()
+	///     .build();
+	/// assert_eq!(patt, b"^%$5$");
+	/// ```
+	pub fn end(&mut self) -> &mut Self {
+		self.text("$")
+	}
+
+	/// The number of captures so far, including position captures and
+	/// any which are still open
+	///
+	/// ```
+	/// let mut b = lua_patterns2::LuaPatternBuilder::new();
+	/// b.text("(%a+)=").capture(|b| {
+	///     assert_eq!(b.capture_count(), 2);
+	///     b.text("%d+()")
+	/// });
+	/// assert_eq!(b.capture_count(), 3);
+	/// b.text("[(]%(");
+	/// assert_eq!(b.capture_count(), 3);
+	/// ```
+	pub fn capture_count(&self) -> usize {
+		count_captures(&self.bytes)
+	}
+
 	/// Create the pattern
+	///
+	/// Panics if a call could not add to the pattern; `try_build` returns
+	/// that as an error instead.
 	pub fn build(&mut self) -> Vec<u8> {
+		if let Some(err) = self.misuse.take() {
+			panic!("{}", err);
+		}
 		let mut v = Vec::new();
 		core::mem::swap(&mut self.bytes, &mut v);
+		self.single = false;
+		self.steps.clear();
 		v
 	}
 
+	/// Create the pattern, if it is valid
+	///
+	/// Otherwise the error says which call to the builder, counting from
+	/// zero, made the pattern invalid, and the builder is left as it was.
+	/// A call which could not add to the pattern, like `backref(0)`, is
+	/// always an error, as nothing added later can fix it.
+	///
+	/// ```
+	/// use lua_patterns2::errors::PatternError;
+	/// let mut b = lua_patterns2::LuaPatternBuilder::new();
+	/// b.text("(%a+)").bytes(b"[").text("[%d");
+	/// let err = b.try_build().unwrap_err();
+	/// assert_eq!(err.step, 2);
+	/// assert_eq!(err.error, PatternError::UnfinishedCharClass.into());
+	/// b.text("]");
+	/// assert_eq!(b.try_build().unwrap(), b"(%a+)%[[%d]");
+	///
+	/// let err = b.bytes(b"x").one_or_more().zero_or_more().try_build().unwrap_err();
+	/// assert_eq!(err.to_string(), "builder step 2: nothing to repeat");
+	/// ```
+	pub fn try_build(&mut self) -> Result<Vec<u8>, BuildError> {
+		if let Err(error) = str_check(&self.bytes) {
+			// the first call after which the pattern stays wrong in the same way
+			let mut step = self.steps.len().saturating_sub(1);
+			while step > 0 && str_check(&self.bytes[..self.steps[step]]) == Err(error) {
+				step -= 1;
+			}
+			return Err(match self.misuse {
+				Some(misuse) if misuse.step < step => misuse,
+				_ => BuildError{step, error: error.into()}
+			});
+		}
+		if let Some(misuse) = self.misuse {
+			return Err(misuse);
+		}
+		Ok(self.build())
+	}
+
 	/// Utility to create a vector of bytes from a hex string
 	///
 	/// ```
@@ -41,3 +41,145 @@ use std::error::Error;
 #[cfg(feature = "std")]
 impl Error for PatternError { }
 
+/// Error returned by `LuaPatternBuilder::try_build`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BuildError {
+	/// Which call to the builder made the pattern invalid, counting from zero
+	pub step: usize,
+	/// What is wrong with the pattern
+	pub error: BuildErrorKind,
+}
+
+/// What is wrong with a pattern from `LuaPatternBuilder`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildErrorKind {
+	/// The pattern itself is invalid
+	Pattern(PatternError),
+	/// `set` or `frontier` with an empty set, which can't be written
+	EmptySet,
+	/// A quantifier with no single character item before it
+	NothingToRepeat,
+	/// `capture` which added nothing, since `()` is a position capture
+	EmptyCapture,
+	/// `backref` with an index not in `1..=9`
+	InvalidBackRef(usize),
+	/// `start` after something was added, where `^` would be a literal
+	MisplacedStart,
+}
+
+impl From<PatternError> for BuildErrorKind {
+	fn from(error: PatternError) -> Self {
+		Self::Pattern(error)
+	}
+}
+
+impl fmt::Display for BuildErrorKind {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Pattern(error) => error.fmt(f),
+			Self::EmptySet => write!(f, "empty set in pattern"),
+			Self::NothingToRepeat => write!(f, "nothing to repeat"),
+			Self::EmptyCapture => write!(f, "empty capture in pattern; use position()"),
+			Self::InvalidBackRef(index) => write!(f, "back reference %{} out of range", index),
+			Self::MisplacedStart => write!(f, "start() must come first in a pattern"),
+		}
+	}
+}
+
+impl fmt::Display for BuildError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "builder step {}: {}", self.step, self.error)
+	}
+}
+
+#[cfg(feature = "std")]
+impl Error for BuildError { }
+
+/// Error returned when a pattern has no regular expression equivalent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegexError {
+	/// `%bxy`, which needs counting
+	Balance,
+	/// `%1` to `%9`
+	BackRef,
+	/// `%f[set]`, when lookaround is not allowed
+	Frontier,
+}
+
+impl fmt::Display for RegexError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Balance => write!(f, "'%b' has no regular expression equivalent"),
+			Self::BackRef => write!(f, "back references have no regular expression equivalent"),
+			Self::Frontier => write!(f, "'%f' needs lookaround"),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl Error for RegexError { }
+
+/// Error returned for a bad `gsub` replacement template, holding the byte
+/// offset of the offending `%`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplateError {
+	/// `%` followed by something other than a digit or `%`
+	InvalidEscape(usize),
+	/// `%n` where the pattern has fewer than `n` captures; also holds `n`
+	InvalidCaptureIndex(usize, usize),
+	/// In the extended syntax, a malformed `%U`, `%L` or `%{...}`
+	InvalidDirective(usize),
+}
+
+impl TemplateError {
+	/// Where the error is in the template
+	pub fn position(&self) -> usize {
+		match *self {
+			Self::InvalidEscape(i) | Self::InvalidCaptureIndex(i, _) | Self::InvalidDirective(i) => i,
+		}
+	}
+}
+
+impl fmt::Display for TemplateError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::InvalidEscape(i) => write!(f, "invalid use of '%' in replacement string at {}", i),
+			Self::InvalidCaptureIndex(i, n) => write!(f, "invalid capture index %{} in replacement string at {}", n, i),
+			Self::InvalidDirective(i) => write!(f, "invalid directive in replacement string at {}", i),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl Error for TemplateError { }
+
+/// Error returned when a regular expression has no Lua pattern equivalent,
+/// holding the byte offset of the problem
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FromRegexError {
+	/// `a|b`
+	Alternation(usize),
+	/// A quantifier on a group or a multi-byte character, like `(ab)+`
+	GroupQuantifier(usize),
+	/// Valid, but outside the supported subset, like `\b` or `(?i)`
+	Unsupported(usize),
+	/// Not a valid regular expression, like `[a` or `a{2`
+	Syntax(usize),
+	/// A repeat count above [MAX_REGEX_REPEAT](crate::MAX_REGEX_REPEAT), like `a{5000}`
+	TooManyRepeats(usize),
+}
+
+impl fmt::Display for FromRegexError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Self::Alternation(i) => write!(f, "alternation at {} has no Lua pattern equivalent", i),
+			Self::GroupQuantifier(i) => write!(f, "quantifier at {} applies to more than one character", i),
+			Self::Unsupported(i) => write!(f, "unsupported regular expression syntax at {}", i),
+			Self::Syntax(i) => write!(f, "malformed regular expression at {}", i),
+			Self::TooManyRepeats(i) => write!(f, "repeat count at {} is too large", i),
+		}
+	}
+}
+
+#[cfg(feature = "std")]
+impl Error for FromRegexError { }
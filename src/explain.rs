@@ -0,0 +1,180 @@
+//! Describe what each part of a pattern does, in plain English.
+//!
+//! ```
+//! use lua_patterns2::LuaPattern;
+//!
+//! let lines: Vec<String> = LuaPattern::new("^(%a+)=.-$").explain()
+//!     .iter().map(|l| l.to_string()).collect();
+//! assert_eq!(lines, &[
+//!     "^: anchor at the start of the text",
+//!     "(: capture #1 begins",
+//!     "  %a+: greedily match a letter, one or more times, as many as possible",
+//!     "): capture #1 ends",
+//!     "=: match '='",
+//!     ".-: lazily match any byte, as few times as possible",
+//!     "$: anchor at the end of the text",
+//! ]);
+//! ```
+
+use core::fmt;
+use std::format;
+use std::string::{String, ToString};
+use std::vec::Vec;
+
+use crate::ast::{self, Class, Item, Quantifier, Set};
+use crate::LuaPattern;
+
+/// One line of an explanation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExplainLine {
+	/// How many captures this part is inside
+	pub depth: usize,
+	/// The part of the pattern explained
+	pub fragment: String,
+	/// What it does
+	pub text: String,
+}
+
+impl fmt::Display for ExplainLine {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		for _ in 0..self.depth {
+			f.write_str("  ")?;
+		}
+		write!(f, "{}: {}", self.fragment, self.text)
+	}
+}
+
+fn class_name(class: Class) -> &'static str {
+	match class {
+		Class::Letter => "letter",
+		Class::Control => "control character",
+		Class::Digit => "digit",
+		Class::Graphic => "printable character",
+		Class::Lower => "lowercase letter",
+		Class::Punct => "punctuation character",
+		Class::Space => "whitespace character",
+		Class::Upper => "uppercase letter",
+		Class::AlphaNum => "word character",
+		Class::HexDigit => "hex digit",
+	}
+}
+
+// "digit" or "non-digit"
+fn class_noun(class: Class, negated: bool) -> String {
+	if negated {
+		format!("non-{}", class_name(class))
+	} else {
+		class_name(class).to_string()
+	}
+}
+
+fn with_article(noun: String) -> String {
+	let article = if noun.starts_with(['a', 'e', 'i', 'o', 'u']) {"an"} else {"a"};
+	format!("{} {}", article, noun)
+}
+
+fn byte_name(c: u8) -> String {
+	let escaped: String = core::ascii::escape_default(c).map(char::from).collect();
+	format!("'{}'", escaped)
+}
+
+fn set_noun(set: &Set) -> String {
+	let mut parts: Vec<String> = set.classes.iter()
+		.map(|&(class, negated)| with_article(class_noun(class, negated)))
+		.collect();
+	for &(lo, hi) in &set.ranges {
+		if lo == hi {
+			parts.push(byte_name(lo));
+		} else {
+			parts.push(format!("{} to {}", byte_name(lo), byte_name(hi)));
+		}
+	}
+	match (set.negated, parts.is_empty()) {
+		(false, true) => "nothing".to_string(),
+		(true, true) => "any byte".to_string(),
+		(false, false) => format!("a byte matching any of: {}", parts.join(", ")),
+		(true, false) => format!("a byte matching none of: {}", parts.join(", ")),
+	}
+}
+
+// what a single character item matches
+fn single_noun(item: &Item) -> String {
+	match *item {
+		Item::Literal(c) => byte_name(c),
+		Item::Any => "any byte".to_string(),
+		Item::Class{class, negated} => with_article(class_noun(class, negated)),
+		Item::Set(ref set) => set_noun(set),
+		_ => "something".to_string()
+	}
+}
+
+fn frontier_text(set: &Set) -> String {
+	match (set.ranges.is_empty(), set.classes.as_slice()) {
+		(true, &[(class, negated)]) => {
+			let negated = negated != set.negated;
+			format!("frontier: transition from {} to {}",
+				class_noun(class, ! negated), class_noun(class, negated))
+		},
+		_ => format!("frontier: transition from a byte not in {} to one in it",
+			String::from_utf8_lossy(&ast::print(&[Item::Set(set.clone())])))
+	}
+}
+
+fn explain_items(items: &[Item], depth: usize, lines: &mut Vec<ExplainLine>) {
+	for item in items {
+		let text = match *item {
+			Item::Start => "anchor at the start of the text".to_string(),
+			Item::End => "anchor at the end of the text".to_string(),
+			Item::Quantified{ref item, quantifier} => {
+				let noun = single_noun(item);
+				match quantifier {
+					Quantifier::ZeroOrMore => format!("greedily match {}, zero or more times, as many as possible", noun),
+					Quantifier::OneOrMore => format!("greedily match {}, one or more times, as many as possible", noun),
+					Quantifier::Lazy => format!("lazily match {}, as few times as possible", noun),
+					Quantifier::Optional => format!("optionally match {}, once or not at all", noun),
+				}
+			},
+			Item::Capture{index, ref items} => {
+				lines.push(ExplainLine{
+					depth,
+					fragment: "(".to_string(),
+					text: format!("capture #{} begins", index)
+				});
+				explain_items(items, depth + 1, lines);
+				lines.push(ExplainLine{
+					depth,
+					fragment: ")".to_string(),
+					text: format!("capture #{} ends", index)
+				});
+				continue;
+			},
+			Item::PositionCapture{index} => format!("capture #{}: the current position", index),
+			Item::Balance{open, close} =>
+				format!("match text balanced between {} and {}", byte_name(open), byte_name(close)),
+			Item::Frontier(ref set) => frontier_text(set),
+			Item::BackRef(l) => format!("match the text of capture #{} again", l.get()),
+			ref single => format!("match {}", single_noun(single))
+		};
+		let fragment = String::from_utf8_lossy(&ast::print(core::slice::from_ref(item))).into_owned();
+		lines.push(ExplainLine{depth, fragment, text});
+	}
+}
+
+impl <'a> LuaPattern<'a> {
+	/// Describe each part of the pattern, one per line
+	///
+	/// Parts inside captures have a greater `depth`; the `Display` form
+	/// indents them by two spaces for each level.
+	///
+	/// ```
+	/// let m = lua_patterns2::LuaPattern::new("%f[%w]%w+");
+	/// let lines = m.explain();
+	/// assert_eq!(lines[0].fragment, "%f[%w]");
+	/// assert_eq!(lines[0].text, "frontier: transition from non-word character to word character");
+	/// ```
+	pub fn explain(&self) -> Vec<ExplainLine> {
+		let mut lines = Vec::new();
+		explain_items(&self.parse(), 0, &mut lines);
+		lines
+	}
+}
@@ -0,0 +1,136 @@
+//! Convert shell globs into Lua patterns, for matching paths.
+//!
+//! ```
+//! use lua_patterns2::{glob_pattern, LuaPattern};
+//!
+//! let patt = glob_pattern("logs/**/access-??.log");
+//! let mut m = LuaPattern::from_bytes(&patt);
+//! assert!(m.matches("logs/access-01.log"));
+//! assert!(m.matches("logs/2024/05/access-02.log"));
+//! assert!(! m.matches("logs/access-1.log"));
+//! assert!(! m.matches("old/logs/access-01.log"));
+//! ```
+
+use std::vec::Vec;
+
+use crate::ast::Set;
+use crate::LuaPatternBuilder;
+
+// `[...]` starting at `i`, and where it ends; None if it is not closed
+fn glob_set(g: &[u8], i: usize) -> Option<(Set, usize)> {
+	let mut j = i + 1;
+	let negated = matches!(g.get(j), Some(b'!' | b'^'));
+	if negated {
+		j += 1;
+	}
+	let mut set = Set{negated, ..Set::default()};
+	let first = j;
+	loop {
+		let mut c = *g.get(j)?;
+		if c == b']' && j != first {
+			break;
+		}
+		if c == b'\\' {
+			j += 1;
+			c = *g.get(j)?;
+		}
+		let ranged = g.get(j + 1) == Some(&b'-') && g.get(j + 2).is_some() && g.get(j + 2) != Some(&b']');
+		if ranged {
+			j += 2;
+			if g[j] == b'\\' {
+				j += 1;
+			}
+			let hi = *g.get(j)?;
+			// a backwards range matches nothing
+			if c <= hi {
+				set.ranges.push((c, hi));
+			}
+		} else {
+			set.ranges.push((c, c));
+		}
+		j += 1;
+	}
+	// not even a negated set matches the separator
+	if negated {
+		set.ranges.push((b'/', b'/'));
+	} else if set.ranges.is_empty() {
+		// matches nothing, as "[]" can't be written
+		set = Set::default().range(0, 255).negate();
+	}
+	Some((set, j + 1))
+}
+
+/// Convert a shell glob into an anchored Lua pattern
+///
+/// `*` matches any run of bytes other than `/`, and `?` any one of them.
+/// `[a-c]`, `[!a-c]` and `[^a-c]` are sets, which as in Lua match single
+/// bytes, so only ASCII belongs in them. A `[` without a matching `]` is
+/// literal, and so is anything escaped with `\`.
+///
+/// A `**` which is a whole path component matches any number of
+/// directories: `**/x` matches `x` and `a/b/x`, and `a/**` everything
+/// under `a`. Anywhere else it is the same as `*`.
+///
+/// ```
+/// use lua_patterns2::{glob_pattern, LuaPattern};
+/// assert_eq!(glob_pattern("*.log"), b"^[^/]*%.log$");
+///
+/// let patt = glob_pattern("[a-c]*[!~]");
+/// let mut m = LuaPattern::from_bytes(&patt);
+/// assert!(m.matches("backup.txt"));
+/// assert!(! m.matches("backup.txt~"));
+/// assert!(! m.matches("a/b"));
+/// ```
+pub fn glob_pattern(glob: &str) -> Vec<u8> {
+	let g = glob.as_bytes();
+	let mut b = LuaPatternBuilder::new();
+	b.text("^");
+	// bytes to add escaped
+	let mut literal = Vec::new();
+	let mut i = 0;
+	while i < g.len() {
+		let c = g[i];
+		if c == b'\\' && i + 1 < g.len() {
+			literal.push(g[i + 1]);
+			i += 2;
+			continue;
+		}
+		let set = if c == b'[' {glob_set(g, i)} else {None};
+		if ! matches!(c, b'*' | b'?') && set.is_none() {
+			literal.push(c);
+			i += 1;
+			continue;
+		}
+		b.bytes(&literal);
+		literal.clear();
+		if let Some((set, end)) = set {
+			b.set(|_| set);
+			i = end;
+			continue;
+		}
+		if c == b'?' {
+			b.text("[^/]");
+			i += 1;
+			continue;
+		}
+		let stars = g[i..].iter().take_while(|&&c| c == b'*').count();
+		let component = stars == 2 && (i == 0 || g[i - 1] == b'/');
+		i += stars;
+		match g.get(i) {
+			// nothing, or anything ending in '/'
+			Some(b'/') if component => {
+				b.text(".-%f[^/\0]");
+				i += 1;
+			},
+			None if component => {
+				b.text(".*");
+			},
+			_ => {
+				b.text("[^/]*");
+			}
+		}
+	}
+	b.bytes(&literal);
+	b.text("$");
+	b.build()
+}
@@ -0,0 +1,207 @@
+//! Static facts about a pattern, derived without running a match.
+//!
+//! ```
+//! let m = lua_patterns2::LuaPattern::new("^(%d+)-%a?$");
+//! let info = m.info();
+//! assert_eq!(info.min_len, 2);
+//! assert_eq!(info.max_len, None);
+//! assert!(info.anchored_start);
+//! assert!(info.anchored_end);
+//! ```
+
+#[cfg(feature = "std")]
+use core::ops;
+
+use crate::luapat::LUA_MAXCAPTURES;
+
+/// What is known about a pattern before matching it
+///
+/// Lengths are in bytes and describe the full match (capture 0).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternInfo {
+	/// No match can be shorter than this
+	pub min_len: usize,
+	/// No match can be longer than this, if the pattern is bounded
+	pub max_len: Option<usize>,
+	/// The pattern begins with `^`, so it only matches at the start
+	pub anchored_start: bool,
+	/// The pattern ends with `$`, so it only matches at the end
+	pub anchored_end: bool,
+	/// The number of captures, counting position captures
+	pub captures: usize,
+}
+
+// mirrors MatchState::classend, but stays inside the slice
+fn class_end(p: &[u8], i: usize) -> usize {
+	match p[i] {
+		b'%' => i + 2,
+		b'[' => {
+			let mut j = i + 1;
+			if p.get(j) == Some(&b'^') {
+				j += 1;
+			}
+			while j < p.len() && p[j] != b']' {
+				if p[j] == b'%' {
+					j += 1;
+				}
+				j += 1;
+			}
+			j + 1
+		},
+		_ => i + 1
+	}
+}
+
+/// One element of a pattern, as `patt_match` sees it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Item<'p> {
+	/// `(`
+	Open,
+	/// `)`
+	Close,
+	/// `()`
+	Position,
+	/// `$` as the last character
+	End,
+	/// `%bxy`
+	Balance(u8, u8),
+	/// `%f[set]`, holding the set
+	Frontier(&'p [u8]),
+	/// `%1` to `%9`, as a capture index from zero
+	BackRef(usize),
+	/// a character class with an optional `*+-?` suffix
+	Single(&'p [u8], Option<u8>),
+}
+
+/// Iterator over the items of a pattern which has passed `str_check`,
+/// not counting any leading `^`
+pub(crate) struct Items<'p> {
+	p: &'p [u8],
+	i: usize,
+}
+
+impl <'p> Items<'p> {
+	pub(crate) fn new(p: &'p [u8]) -> Items<'p> {
+		let i = if p.first() == Some(&b'^') {1} else {0};
+		Items{p, i}
+	}
+
+	/// Each item with where it is in the pattern
+	#[cfg(feature = "std")]
+	pub(crate) fn spanned(mut self) -> impl Iterator<Item = (ops::Range<usize>, Item<'p>)> {
+		core::iter::from_fn(move || {
+			let start = self.i;
+			self.next().map(|item| (start..self.i, item))
+		})
+	}
+}
+
+impl <'p> Iterator for Items<'p> {
+	type Item = Item<'p>;
+
+	fn next(&mut self) -> Option<Item<'p>> {
+		let p = self.p;
+		let i = self.i;
+		if i >= p.len() {
+			return None;
+		}
+		let (item, next) = match p[i] {
+			b'(' if p.get(i + 1) == Some(&b')') => (Item::Position, i + 2),
+			b'(' => (Item::Open, i + 1),
+			b')' => (Item::Close, i + 1),
+			b'$' if i + 1 == p.len() => (Item::End, i + 1),
+			b'%' if p[i + 1] == b'b' => (Item::Balance(p[i + 2], p[i + 3]), i + 4),
+			b'%' if p[i + 1] == b'f' => {
+				let ep = class_end(p, i + 2);
+				(Item::Frontier(&p[i + 2..ep]), ep)
+			},
+			b'%' if p[i + 1].is_ascii_digit() => (Item::BackRef((p[i + 1] - b'1') as usize), i + 2),
+			_ => {
+				let ep = class_end(p, i);
+				match p.get(ep) {
+					Some(&q) if q == b'*' || q == b'+' || q == b'-' || q == b'?' =>
+						(Item::Single(&p[i..ep], Some(q)), ep + 1),
+					_ => (Item::Single(&p[i..ep], None), ep)
+				}
+			}
+		};
+		self.i = next;
+		Some(item)
+	}
+}
+
+fn add_len(max: Option<usize>, len: Option<usize>) -> Option<usize> {
+	match (max, len) {
+		(Some(a), Some(b)) => Some(a + b),
+		_ => None
+	}
+}
+
+/// Analyse a pattern which has already passed `str_check`
+pub(crate) fn pattern_info(p: &[u8]) -> PatternInfo {
+	let mut anchored_end = false;
+	let mut min = 0;
+	let mut max = Some(0);
+
+	// lengths of finished captures, for back references
+	let mut captures = [(0, Some(0)); LUA_MAXCAPTURES];
+	let mut level = 0;
+	// capture level and lengths so far, for each open capture
+	let mut open = [(0, 0, Some(0)); LUA_MAXCAPTURES];
+	let mut n_open = 0;
+
+	for item in Items::new(p) {
+		match item {
+			Item::Position => {
+				captures[level] = (0, Some(0));
+				level += 1;
+			},
+			Item::Open => {
+				open[n_open] = (level, min, max);
+				n_open += 1;
+				level += 1;
+			},
+			Item::Close => {
+				n_open -= 1;
+				let (l, start_min, start_max) = open[n_open];
+				let len_max = match (max, start_max) {
+					(Some(a), Some(b)) => Some(a - b),
+					_ => None
+				};
+				captures[l] = (min - start_min, len_max);
+			},
+			Item::End => anchored_end = true,
+			Item::Balance(..) => {
+				// a balanced match is at least the two delimiters
+				min += 2;
+				max = None;
+			},
+			Item::Frontier(_) => {},
+			Item::BackRef(l) => {
+				let (cmin, cmax) = captures[l];
+				min += cmin;
+				max = add_len(max, cmax);
+			},
+			Item::Single(_, suffix) => match suffix {
+				Some(b'*') | Some(b'-') => max = None,
+				Some(b'+') => {
+					min += 1;
+					max = None;
+				},
+				Some(_) => max = add_len(max, Some(1)),
+				None => {
+					min += 1;
+					max = add_len(max, Some(1));
+				}
+			}
+		}
+	}
+
+	PatternInfo{
+		min_len: min,
+		max_len: max,
+		anchored_start: p.first() == Some(&b'^'),
+		anchored_end,
+		captures: level,
+	}
+}
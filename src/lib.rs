@@ -51,22 +51,63 @@ use core::ops;
 #[cfg(feature = "std")]
 use std::vec::Vec;
 #[cfg(feature = "std")]
-use std::string::{String, ToString};
+use std::string::String;
+#[cfg(feature = "std")]
+use std::borrow::Cow;
+#[cfg(feature = "std")]
+use core::convert::Infallible;
 
 
 pub mod errors;
 use crate::errors::*;
 
+pub mod info;
+pub use crate::info::*;
+
 #[cfg(feature = "std")]
 pub mod builder;
 #[cfg(feature = "std")]
 pub use crate::builder::*;
 
+pub mod template;
+pub use crate::template::*;
+
 #[cfg(feature = "std")]
 pub mod subst;
 #[cfg(feature = "std")]
 pub use crate::subst::*;
 
+#[cfg(feature = "std")]
+pub mod stream;
+#[cfg(feature = "std")]
+pub use crate::stream::*;
+
+#[cfg(feature = "std")]
+pub mod set;
+#[cfg(feature = "std")]
+pub use crate::set::*;
+
+#[cfg(feature = "std")]
+pub mod ast;
+
+#[cfg(feature = "std")]
+pub mod lint;
+
+#[cfg(feature = "std")]
+pub mod explain;
+#[cfg(feature = "std")]
+pub use crate::explain::*;
+
+#[cfg(feature = "std")]
+pub mod regex;
+#[cfg(feature = "std")]
+pub use crate::regex::*;
+
+#[cfg(feature = "std")]
+pub mod glob;
+#[cfg(feature = "std")]
+pub use crate::glob::*;
+
 mod luapat;
 use crate::luapat::*;
 
@@ -74,12 +115,36 @@ use crate::luapat::*;
 #[cfg(feature = "heapless")]
 type PartialResult<T> = Result<T, T>;
 
+/// The outcome of matching input which may be incomplete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartialMatch {
+	/// The input matches as it is
+	Matched,
+	/// No amount of further input can make it match
+	NoMatch,
+	/// There is no match yet, but more input could produce one
+	Incomplete,
+}
+
+/// How to choose between matches which start at the same place
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatchKind {
+	/// The first match found by backtracking, as in Lua
+	LeftmostFirst,
+	/// The longest match, trying every alternative at the leftmost start
+	///
+	/// This can be much slower for patterns with several repeated items.
+	LeftmostLongest,
+}
+
 /// Represents a Lua string pattern and the results of a match
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct LuaPattern<'a> {
 	patt: &'a [u8],
 	matches: [LuaMatch; LUA_MAXCAPTURES],
-	n_match: usize
+	n_match: usize,
+	info: PatternInfo,
+	kind: MatchKind
 }
 
 impl <'a> LuaPattern<'a> {
@@ -87,7 +152,7 @@ impl <'a> LuaPattern<'a> {
 	pub fn from_bytes_try (bytes: &'a [u8]) -> Result<LuaPattern<'a>, PatternError> {
 		str_check(bytes)?;
 		let matches = [LuaMatch{start: 0, end: 0}; LUA_MAXCAPTURES];
-		Ok(LuaPattern{patt: bytes, matches: matches, n_match: 0})
+		Ok(LuaPattern{patt: bytes, matches, n_match: 0, info: pattern_info(bytes), kind: MatchKind::LeftmostFirst})
 	}
 
 	/// Maybe create a new Lua pattern from a string
@@ -105,6 +170,37 @@ impl <'a> LuaPattern<'a> {
 		LuaPattern::from_bytes_try(bytes).expect("bad pattern")
 	}
 
+	/// Choose how to pick between matches starting at the same place
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, MatchKind};
+	/// let mut m = LuaPattern::new("%a-%d?");
+	/// assert!(m.matches("abc1x"));
+	/// assert_eq!(m.range(), 0..0);
+	/// let mut m = m.with_match_kind(MatchKind::LeftmostLongest);
+	/// assert!(m.matches("abc1x"));
+	/// assert_eq!(m.range(), 0..4);
+	/// ```
+	pub fn with_match_kind(mut self, kind: MatchKind) -> LuaPattern<'a> {
+		self.kind = kind;
+		self
+	}
+
+	/// Facts about the pattern found when it was created
+	///
+	/// ```
+	/// let m = lua_patterns2::LuaPattern::new("%d%d?%.txt$");
+	/// let info = m.info();
+	/// assert_eq!(info.min_len, 5);
+	/// assert_eq!(info.max_len, Some(6));
+	/// assert!(! info.anchored_start);
+	/// assert!(info.anchored_end);
+	/// assert_eq!(info.captures, 0);
+	/// ```
+	pub fn info(&self) -> PatternInfo {
+		self.info
+	}
+
 	/// Match a slice of bytes with a pattern
 	///
 	/// ```
@@ -115,7 +211,7 @@ impl <'a> LuaPattern<'a> {
 	/// assert_eq!(&bytes[m.range()], &[0xFE,0xEE,0xEE,0xED]);
 	/// ```
 	pub fn matches_bytes(&mut self, s: &[u8]) -> bool {
-		self.n_match = str_match(s, self.patt, &mut self.matches).unwrap();
+		self.n_match = str_match(s, self.patt, &self.info, self.kind, &mut self.matches).unwrap();
 		self.n_match > 0
 	}
 
@@ -130,6 +226,92 @@ impl <'a> LuaPattern<'a> {
 		self.matches_bytes(text.as_bytes())
 	}
 
+	/// Match a slice of bytes with a pattern, taking the match which
+	/// starts furthest to the right
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(&[0x01,b'+']);
+	/// let bytes = &[0x01,0x01,0x00,0x01,0x01,0x01];
+	/// assert!(m.rmatches_bytes(bytes));
+	/// assert_eq!(m.range(), 5..6);
+	/// ```
+	pub fn rmatches_bytes(&mut self, s: &[u8]) -> bool {
+		self.n_match = str_rmatch(s, self.patt, &self.info, self.kind, &mut self.matches).unwrap();
+		self.n_match > 0
+	}
+
+	/// Match a string with a pattern, taking the match which
+	/// starts furthest to the right
+	///
+	/// Note that this is the _rightmost-starting_ match, which for
+	/// greedy patterns may be shorter than the last match `gmatch` finds.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("/(%w+)");
+	/// let text = "/usr/local/bin";
+	/// assert!(m.rmatches(text));
+	/// assert_eq!(&text[m.capture(1)], "bin");
+	/// ```
+	pub fn rmatches(&mut self, text: &str) -> bool {
+		self.rmatches_bytes(text.as_bytes())
+	}
+
+	/// Find the rightmost-starting match in a string, returning its captures
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("(%a+)=(%d+)");
+	/// let cc = m.rfind("a=1; b=2; c=3").unwrap();
+	/// assert_eq!(cc.get(1), "c");
+	/// assert_eq!(cc.get(2), "3");
+	/// ```
+	pub fn rfind<'b,'c>(&'c mut self, text: &'b str) -> Option<Captures<'a,'b,'c>> {
+		if self.rmatches(text) {
+			Some(self.match_captures(text))
+		} else {
+			None
+		}
+	}
+
+	/// Match a slice of bytes which may only be the start of the input
+	///
+	/// Besides matching or not, this tells whether the match failed
+	/// because it ran out of input. Anchor the pattern with `^` to parse
+	/// from the start of a buffer; an unanchored pattern can always
+	/// match further along.
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, PartialMatch};
+	/// let mut m = LuaPattern::new("^GET (%S+) HTTP/1%.%d\r\n");
+	/// assert_eq!(m.match_partial_bytes(b"GET /ind"), PartialMatch::Incomplete);
+	/// assert_eq!(m.match_partial_bytes(b"PUT /index"), PartialMatch::NoMatch);
+	/// assert_eq!(m.match_partial_bytes(b"GET /index HTTP/1.1\r\n"), PartialMatch::Matched);
+	/// assert_eq!(m.capture(1), 4..10);
+	/// ```
+	pub fn match_partial_bytes(&mut self, s: &[u8]) -> PartialMatch {
+		let (n, hit_end) = str_match_partial(s, self.patt, &self.info, self.kind, &mut self.matches).unwrap();
+		self.n_match = n;
+		if n > 0 {
+			PartialMatch::Matched
+		} else if hit_end {
+			PartialMatch::Incomplete
+		} else {
+			PartialMatch::NoMatch
+		}
+	}
+
+	/// Match a string which may only be the start of the input
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, PartialMatch};
+	/// let mut m = LuaPattern::new("^(%d+);");
+	/// assert_eq!(m.match_partial("12"), PartialMatch::Incomplete);
+	/// assert_eq!(m.match_partial("12,"), PartialMatch::NoMatch);
+	/// assert_eq!(m.match_partial("12;"), PartialMatch::Matched);
+	/// ```
+	pub fn match_partial(&mut self, text: &str) -> PartialMatch {
+		self.match_partial_bytes(text.as_bytes())
+	}
+
 	/// Match a string, returning first capture if successful
 	///
 	/// ```
@@ -310,6 +492,22 @@ impl <'a> LuaPattern<'a> {
 		GMatch{m: self, text: text}
 	}
 
+	/// An iterator over all matches in a string, starting from the end.
+	///
+	/// Each match is the rightmost-starting one in the text before the
+	/// previous match, so (just as with `gmatch`) anchors refer to that
+	/// shorter text. Use a frontier to find whole words, since "%w+"
+	/// alone would start at the last letter.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("%f[%w]%w+");
+	/// let split: Vec<_> = m.rgmatch("dog  cat leopard wolf").collect();
+	/// assert_eq!(split,&["wolf","leopard","cat","dog"]);
+	/// ```
+	pub fn rgmatch<'b,'c>(&'c mut self, text: &'b str) -> RGMatch<'a,'b,'c> {
+		RGMatch{m: self, text, done: false}
+	}
+
 	/// An iterator over all captures in a string.
 	///
 	/// The matches are returned as captures; this is a _streaming_
@@ -341,6 +539,47 @@ impl <'a> LuaPattern<'a> {
 		GMatchBytes{m: self, bytes: bytes}
 	}
 
+	// The loop behind every gsub. `keep` writes a range of `bytes` as it
+	// is, and `replace` the match just found, given where the slice it was
+	// found in starts and the number of the match; an error from either
+	// stops the loop. Gives the number of matches replaced and where the
+	// rest of `bytes` starts, which is left for the caller to write.
+	//
+	// As in Lua, the byte after an empty match is kept before looking
+	// again, or the whole character if `bytes` is a `str`.
+	fn gsub_driver<O, E, K, R>(&mut self, bytes: &[u8], utf8: bool, max: usize, out: &mut O, mut keep: K, mut replace: R) -> (usize, Result<usize, E>)
+	where K: FnMut(&mut O, ops::Range<usize>) -> Result<(), E>, R: FnMut(&mut O, &Self, usize, usize) -> Result<(), E> {
+		let mut n = 0;
+		let mut start = 0;
+		let mut run = || -> Result<(), E> {
+			while n < max && self.matches_bytes(&bytes[start..]) {
+				let all = self.range();
+				keep(out, start..start + all.start)?;
+				replace(out, self, start, n)?;
+				n += 1;
+				start += all.end;
+				if all.is_empty() {
+					if start == bytes.len() {
+						break;
+					}
+					let step = if utf8 {
+						1 + bytes[start + 1..].iter().take_while(|&&c| c & 0xC0 == 0x80).count()
+					} else {
+						1
+					};
+					keep(out, start..start + step)?;
+					start += step;
+				}
+				if self.info.anchored_start {
+					break;
+				}
+			}
+			Ok(())
+		};
+		let res = run();
+		(n, res.map(|()| start))
+	}
+
 	/// Globally substitute all matches with a replacement
 	/// provided by a function of the captures.
 	///
@@ -353,20 +592,112 @@ impl <'a> LuaPattern<'a> {
 	/// ```
 	#[cfg(feature = "std")]
 	pub fn gsub_with<F> (&mut self, text: &str, lookup: F) -> String
-	where F: Fn(Captures)-> String {
-		let mut slice = text;
+	where F: FnMut(Captures)-> String {
+		self.gsub_with_n(text, lookup, usize::MAX).0
+	}
+
+	/// Globally substitute all matches with a replacement
+	/// provided by a function of the captures, borrowing the text
+	/// if nothing matches.
+	///
+	/// ```
+	/// use std::borrow::Cow;
+	/// let mut m = lua_patterns2::LuaPattern::new("%c");
+	/// let res = m.gsub_with_cow("plain", |cc| "?".to_string());
+	/// assert!(matches!(res, Cow::Borrowed("plain")));
+	/// let res = m.gsub_with_cow("bell\x07", |cc| "?".to_string());
+	/// assert_eq!(res, "bell?");
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_with_cow<'t, F> (&mut self, text: &'t str, mut lookup: F) -> Cow<'t, str>
+	where F: FnMut(Captures)-> String {
+		let mut res = String::new();
+		let (n, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, usize::MAX, &mut res, keep_str(text), |res, m, start, _| {
+			res.push_str(&lookup(Captures{m, text: &text[start..]}));
+			Ok(())
+		});
+		if n == 0 {
+			return Cow::Borrowed(text);
+		}
+		res.push_str(&text[rest..]);
+		Cow::Owned(res)
+	}
+
+	/// Substitute at most `max` matches with a replacement
+	/// provided by a function of the captures, also returning
+	/// the number of substitutions.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("%$(%S+)");
+	/// let res = m.gsub_with_n("hello $dolly you're so $fine!",
+	///     |cc| cc.get(1).to_uppercase(), 1
+	/// );
+	/// assert_eq!(res, ("hello DOLLY you're so $fine!".to_string(), 1));
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_with_n<F> (&mut self, text: &str, mut lookup: F, max: usize) -> (String, usize)
+	where F: FnMut(Captures)-> String {
+		let mut res = String::new();
+		let (n, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, max, &mut res, keep_str(text), |res, m, start, _| {
+			res.push_str(&lookup(Captures{m, text: &text[start..]}));
+			Ok(())
+		});
+		res.push_str(&text[rest..]);
+		(res, n)
+	}
+
+	/// Globally substitute matches with a replacement provided by
+	/// a function of the captures, keeping the match where it gives `None`.
+	///
+	/// ```
+	/// use std::borrow::Cow;
+	/// let mut m = lua_patterns2::LuaPattern::new("%$(%a+)");
+	/// let res = m.gsub_with_maybe("$user owes $amount", |cc| match cc.get(1) {
+	///     "user" => Some(Cow::Borrowed("joe")),
+	///     _ => None
+	/// });
+	/// assert_eq!(res, "joe owes $amount");
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_with_maybe<'t, F> (&mut self, text: &'t str, mut lookup: F) -> String
+	where F: FnMut(Captures<'a, 't, '_>)-> Option<Cow<'t, str>> {
+		let mut res = String::new();
+		let (_, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, usize::MAX, &mut res, keep_str(text), |res, m, start, _| {
+			let slice = &text[start..];
+			match lookup(Captures{m, text: slice}) {
+				Some(repl) => res.push_str(&repl),
+				None => res.push_str(&slice[m.range()])
+			}
+			Ok(())
+		});
+		res.push_str(&text[rest..]);
+		res
+	}
+
+	/// Globally substitute all matches with a replacement
+	/// provided by a function of the captures, the number of the
+	/// match (from 0) and where it starts in `text`.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("%?");
+	/// let mut offsets = Vec::new();
+	/// let res = m.gsub_with_indexed("a=? and b=?", |_, i, offset| {
+	///     offsets.push(offset);
+	///     format!("${}", i + 1)
+	/// });
+	/// assert_eq!(res, "a=$1 and b=$2");
+	/// assert_eq!(offsets, &[2, 10]);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_with_indexed<F> (&mut self, text: &str, mut lookup: F) -> String
+	where F: FnMut(Captures, usize, usize)-> String {
 		let mut res = String::new();
-		while self.matches(slice) {
-			// full range of match
-			let all = self.range();
-			// append everything up to match
-			res.push_str(&slice[0..all.start]);
-			let captures = Captures{m: self, text: slice};
-			let repl = lookup(captures);
-			res.push_str(&repl);
-			slice = &slice[all.end..];
-		}
-		res.push_str(slice);
+		let (_, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, usize::MAX, &mut res, keep_str(text), |res, m, start, index| {
+			let offset = start + m.range().start;
+			res.push_str(&lookup(Captures{m, text: &text[start..]}, index, offset));
+			Ok(())
+		});
+		res.push_str(&text[rest..]);
 		res
 	}
 
@@ -384,27 +715,36 @@ impl <'a> LuaPattern<'a> {
 	/// ```
 	#[cfg(feature = "heapless")]
 	pub fn gsub_with_heapless<F, const N: usize, const M: usize> (&mut self, text: &str, lookup: F) -> PartialResult<heapless::String<N>>
-	where F: Fn(Captures)-> heapless::String<M> {
-		let mut slice = text;
+	where F: FnMut(Captures)-> heapless::String<M> {
+		self.gsub_with_heapless_n(text, lookup, usize::MAX).map(|r| r.0).map_err(|r| r.0)
+	}
+
+	/// Substitute at most `max` matches with a replacement
+	/// provided by a function of the captures, also returning
+	/// the number of substitutions.
+	///
+	/// If there isn't enough space the Err variant will be returned with a partial result.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("%$(%S+)");
+	/// let (res, n) = m.gsub_with_heapless_n::<_, 32, 8>("hello $dolly you're so $fine!",
+	///     |cc| cc.get(1).into(), 1
+	/// ).unwrap();
+	/// assert_eq!(res, "hello dolly you're so $fine!");
+	/// assert_eq!(n, 1);
+	/// ```
+	#[cfg(feature = "heapless")]
+	pub fn gsub_with_heapless_n<F, const N: usize, const M: usize> (&mut self, text: &str, mut lookup: F, max: usize) -> PartialResult<(heapless::String<N>, usize)>
+	where F: FnMut(Captures)-> heapless::String<M> {
 		let mut res = heapless::String::new();
-		while self.matches(slice) {
-			// full range of match
-			let all = self.range();
-			// append everything up to match
-			if let Err(_) = res.push_str(&slice[0..all.start]) {
-				return Err(res);
-			}
-			let captures = Captures{m: self, text: slice};
-			let repl = lookup(captures);
-			if let Err(_) = res.push_str(&repl) {
-				return Err(res);
-			}
-			slice = &slice[all.end..];
-		}
-		if let Err(_) = res.push_str(slice) {
-			return Err(res);
+		let (n, rest) = self.gsub_driver(text.as_bytes(), true, max, &mut res,
+			|res: &mut heapless::String<N>, r| res.push_str(&text[r]),
+			|res, m, start, _| res.push_str(&lookup(Captures{m, text: &text[start..]}))
+		);
+		match rest.and_then(|rest| res.push_str(&text[rest..])) {
+			Ok(()) => Ok((res, n)),
+			Err(()) => Err((res, n))
 		}
-		Ok(res)
 	}
 
 	/// Globally substitute all _byte_ matches with a replacement
@@ -419,19 +759,107 @@ impl <'a> LuaPattern<'a> {
 	/// ```
 	#[cfg(feature = "std")]
 	pub fn gsub_bytes_with<F>(&mut self, bytes: &[u8], lookup: F) -> Vec<u8>
-	where F: Fn(ByteCaptures)-> Vec<u8> {
-		let mut slice = bytes;
+	where F: FnMut(ByteCaptures)-> Vec<u8> {
+		self.gsub_bytes_with_n(bytes, lookup, usize::MAX).0
+	}
+
+	/// Globally substitute all _byte_ matches with a replacement
+	/// provided by a function of the captures, borrowing the bytes
+	/// if nothing matches.
+	///
+	/// ```
+	/// use std::borrow::Cow;
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(&[0x00]);
+	/// let bytes = &[0xAA,0xBB];
+	/// assert!(matches!(m.gsub_bytes_with_cow(bytes, |cc| vec![]), Cow::Borrowed(_)));
+	/// let res = m.gsub_bytes_with_cow(&[0xAA,0x00,0xBB], |cc| vec![]);
+	/// assert_eq!(res.as_ref(), bytes);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_bytes_with_cow<'t, F>(&mut self, bytes: &'t [u8], mut lookup: F) -> Cow<'t, [u8]>
+	where F: FnMut(ByteCaptures)-> Vec<u8> {
 		let mut res = Vec::new();
-		while self.matches_bytes(slice) {
-			let all = self.range();
-			let capture = &slice[0..all.start];
-			res.extend_from_slice(capture);
-			let captures = ByteCaptures{m: self, bytes: slice};
-			let repl = lookup(captures);
-			res.extend(repl);
-			slice = &slice[all.end..];
-		}
-		res.extend_from_slice(slice);
+		let (n, Ok(rest)) = self.gsub_driver(bytes, false, usize::MAX, &mut res, keep_bytes(bytes), |res, m, start, _| {
+			res.extend(lookup(ByteCaptures{m, bytes: &bytes[start..]}));
+			Ok(())
+		});
+		if n == 0 {
+			return Cow::Borrowed(bytes);
+		}
+		res.extend_from_slice(&bytes[rest..]);
+		Cow::Owned(res)
+	}
+
+	/// Substitute at most `max` _byte_ matches with a replacement
+	/// provided by a function of the captures, also returning
+	/// the number of substitutions.
+	///
+	/// ```
+	/// let bytes = &[0xAA,0x01,0x02,0x01,0x02];
+	/// let patt = &[0x01,0x02];
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(patt);
+	/// let (res, n) = m.gsub_bytes_with_n(bytes, |cc| vec![0xFF], 1);
+	/// assert_eq!(res, &[0xAA,0xFF,0x01,0x02]);
+	/// assert_eq!(n, 1);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_bytes_with_n<F>(&mut self, bytes: &[u8], mut lookup: F, max: usize) -> (Vec<u8>, usize)
+	where F: FnMut(ByteCaptures)-> Vec<u8> {
+		let mut res = Vec::new();
+		let (n, Ok(rest)) = self.gsub_driver(bytes, false, max, &mut res, keep_bytes(bytes), |res, m, start, _| {
+			res.extend(lookup(ByteCaptures{m, bytes: &bytes[start..]}));
+			Ok(())
+		});
+		res.extend_from_slice(&bytes[rest..]);
+		(res, n)
+	}
+
+	/// Globally substitute _byte_ matches with a replacement provided by
+	/// a function of the captures, keeping the match where it gives `None`.
+	///
+	/// ```
+	/// let bytes = &[0x01,0xAA,0x01,0xBB];
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(&[0x01,b'(',b'.',b')']);
+	/// let res = m.gsub_bytes_with_maybe(bytes, |cc| {
+	///     if cc.get(1) == &[0xAA] {Some(vec![0xFF].into())} else {None}
+	/// });
+	/// assert_eq!(res, &[0xFF,0x01,0xBB]);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_bytes_with_maybe<'t, F>(&mut self, bytes: &'t [u8], mut lookup: F) -> Vec<u8>
+	where F: FnMut(ByteCaptures<'_, 't>)-> Option<Cow<'t, [u8]>> {
+		let mut res = Vec::new();
+		let (_, Ok(rest)) = self.gsub_driver(bytes, false, usize::MAX, &mut res, keep_bytes(bytes), |res, m, start, _| {
+			let slice = &bytes[start..];
+			match lookup(ByteCaptures{m, bytes: slice}) {
+				Some(repl) => res.extend_from_slice(&repl),
+				None => res.extend_from_slice(&slice[m.range()])
+			}
+			Ok(())
+		});
+		res.extend_from_slice(&bytes[rest..]);
+		res
+	}
+
+	/// Globally substitute all _byte_ matches with a replacement
+	/// provided by a function of the captures, the number of the
+	/// match (from 0) and where it starts in `bytes`.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(&[0x00]);
+	/// let res = m.gsub_bytes_with_indexed(&[0x00,0xAA,0x00], |_, i, offset| vec![i as u8, offset as u8]);
+	/// assert_eq!(res, &[0x00,0x00,0xAA,0x01,0x02]);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_bytes_with_indexed<F>(&mut self, bytes: &[u8], mut lookup: F) -> Vec<u8>
+	where F: FnMut(ByteCaptures, usize, usize)-> Vec<u8> {
+		let mut res = Vec::new();
+		let (_, Ok(rest)) = self.gsub_driver(bytes, false, usize::MAX, &mut res, keep_bytes(bytes), |res, m, start, index| {
+			let offset = start + m.range().start;
+			res.extend(lookup(ByteCaptures{m, bytes: &bytes[start..]}, index, offset));
+			Ok(())
+		});
+		res.extend_from_slice(&bytes[rest..]);
 		res
 	}
 
@@ -449,29 +877,60 @@ impl <'a> LuaPattern<'a> {
 	/// ```
 	#[cfg(feature = "heapless")]
 	pub fn gsub_bytes_with_heapless<F, const N: usize, const M: usize>(&mut self, bytes: &[u8], lookup: F) -> PartialResult<heapless::Vec<u8, N>>
-	where F: Fn(ByteCaptures)-> heapless::Vec<u8, M> {
-		let mut slice = bytes;
+	where F: FnMut(ByteCaptures)-> heapless::Vec<u8, M> {
+		self.gsub_bytes_with_heapless_n(bytes, lookup, usize::MAX).map(|r| r.0).map_err(|r| r.0)
+	}
+
+	/// Substitute at most `max` _byte_ matches with a replacement
+	/// provided by a function of the captures, also returning
+	/// the number of substitutions.
+	///
+	/// If there isn't enough space the Err variant will be returned with a partial result.
+	///
+	/// ```
+	/// let bytes = &[0xAA,0x01,0x02,0x01,0x02];
+	/// let patt = &[0x01,0x02];
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(patt);
+	/// let (res, n) = m.gsub_bytes_with_heapless_n::<_, 8, 1>(bytes,
+	///     |cc| heapless::Vec::from_slice(&[0xFF]).unwrap(), 1
+	/// ).unwrap();
+	/// assert_eq!(res, &[0xAA,0xFF,0x01,0x02]);
+	/// assert_eq!(n, 1);
+	/// ```
+	#[cfg(feature = "heapless")]
+	pub fn gsub_bytes_with_heapless_n<F, const N: usize, const M: usize>(&mut self, bytes: &[u8], mut lookup: F, max: usize) -> PartialResult<(heapless::Vec<u8, N>, usize)>
+	where F: FnMut(ByteCaptures)-> heapless::Vec<u8, M> {
 		let mut res = heapless::Vec::new();
-		while self.matches_bytes(slice) {
-			let all = self.range();
-			let capture = &slice[0..all.start];
-			if let Err(_) = res.extend_from_slice(capture) {
-				return Err(res);
-			}
-			let captures = ByteCaptures{m: self, bytes: slice};
-			let repl = lookup(captures);
-			res.extend(repl);
-			slice = &slice[all.end..];
-		}
-		if let Err(_) = res.extend_from_slice(slice) {
-			return Err(res);
+		let (n, rest) = self.gsub_driver(bytes, false, max, &mut res,
+			|res: &mut heapless::Vec<u8, N>, r| res.extend_from_slice(&bytes[r]),
+			|res, m, start, _| res.extend_from_slice(&lookup(ByteCaptures{m, bytes: &bytes[start..]}))
+		);
+		match rest.and_then(|rest| res.extend_from_slice(&bytes[rest..])) {
+			Ok(()) => Ok((res, n)),
+			Err(()) => Err((res, n))
 		}
-		Ok(res)
 	}
 
 }
 
 
+// a gsub `keep` which copies from `text`
+#[cfg(feature = "std")]
+pub(crate) fn keep_str(text: &str) -> impl FnMut(&mut String, ops::Range<usize>) -> Result<(), Infallible> + '_ {
+	move |res, r| {
+		res.push_str(&text[r]);
+		Ok(())
+	}
+}
+
+// a gsub `keep` which copies from `bytes`
+#[cfg(feature = "std")]
+pub(crate) fn keep_bytes(bytes: &[u8]) -> impl FnMut(&mut Vec<u8>, ops::Range<usize>) -> Result<(), Infallible> + '_ {
+	move |res, r| {
+		res.extend_from_slice(&bytes[r]);
+		Ok(())
+	}
+}
 
 /// Low-overhead convenient access to string match captures
 // note: there are three borrows going on here.
@@ -536,6 +995,33 @@ impl <'a,'b,'c>Iterator for GMatch<'a,'b,'c> {
 
 }
 
+/// Iterator for all string slices from `rgmatch`
+// note lifetimes as for Captures above!
+pub struct RGMatch<'a,'b,'c> where 'a: 'c {
+	m: &'c mut LuaPattern<'a>,
+	text: &'b str,
+	done: bool
+}
+
+impl <'a,'b,'c>Iterator for RGMatch<'a,'b,'c> {
+	type Item = &'b str;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if self.done || ! self.m.rmatches(self.text) {
+			self.done = true;
+			None
+		} else {
+			let slice = &self.text[self.m.first_capture()];
+			let start = self.m.range().start;
+			// nothing can start before the beginning
+			self.done = start == 0;
+			self.text = &self.text[..start];
+			Some(slice)
+		}
+	}
+
+}
+
 /// Unsafe version of Captures, needed for gmatch_captures
 // It's unsafe because the lifetime only depends on the original
 // text, not the borrowed matches.
@@ -609,6 +1095,7 @@ impl <'a,'b>Iterator for GMatchBytes<'a,'b> {
 #[cfg(feature = "std")]
 mod tests {
 	use super::*;
+	use std::string::ToString;
 
 	#[test]
 	fn captures_and_matching() {
@@ -704,15 +1191,906 @@ mod tests {
 		assert_eq!(res,"'2':a '3':b '4':c ");
 	}
 
+	#[test]
+	fn pattern_info() {
+		let info = LuaPattern::new("^(%a+)=%1$").info();
+		assert_eq!(info.min_len, 3);
+		assert_eq!(info.max_len, None);
+		assert!(info.anchored_start && info.anchored_end);
+
+		let info = LuaPattern::new("()(%d%d?)%-%2()").info();
+		assert_eq!(info.min_len, 3);
+		assert_eq!(info.max_len, Some(5));
+
+		let info = LuaPattern::new("%b()[^$]%$").info();
+		assert_eq!(info.min_len, 4);
+		assert!(! info.anchored_end);
+
+		// bailing out early must not change what matches
+		let mut m = LuaPattern::new("%d%d%d$");
+		assert!(! m.matches("12"));
+		assert!(m.matches("a 1234"));
+		assert_eq!(m.range(), 3..6);
+		let mut m = LuaPattern::new("(a+)b%1");
+		assert!(m.matches("xaaaba"));
+		assert_eq!(m.range(), 3..6);
+		assert!(! m.matches("ab"));
+
+		// a back reference to a position capture never matches, as in Lua
+		let mut m = LuaPattern::new("()%1");
+		assert!(! m.matches("abc"));
+		assert!(! m.matches(""));
+	}
+
+	#[test]
+	fn reverse_search() {
+		let mut m = LuaPattern::new("/([^/]+)");
+		let path = "/home/user/file.txt";
+		assert!(m.rmatches(path));
+		assert_eq!(&path[m.capture(1)], "file.txt");
+		assert_eq!(m.range(), 10..19);
+		assert!(! m.rmatches("file.txt"));
+
+		// rightmost start, not last of gmatch
+		let mut m = LuaPattern::new("%d+");
+		assert!(m.rmatches("12 345"));
+		assert_eq!(m.range(), 5..6);
+
+		let mut m = LuaPattern::new("^%a+");
+		assert!(m.rmatches("one two"));
+		assert_eq!(m.range(), 0..3);
+
+		let mut m = LuaPattern::new("(%a+)=(%a+)");
+		let mut iter = m.rgmatch("a=b c=d e=f");
+		assert_eq!(iter.next(), Some("e"));
+		assert_eq!(iter.next(), Some("c"));
+		assert_eq!(iter.next(), Some("a"));
+		assert_eq!(iter.next(), None);
+
+		// empty matches must not repeat forever
+		let mut m = LuaPattern::new("%d*");
+		assert_eq!(m.rgmatch("ab").count(), 2);
+
+		// a long failing scan used to run out of match depth
+		let mut m = LuaPattern::new("ab");
+		assert!(! m.matches(&"a".repeat(1000)));
+		assert!(! m.rmatches(&"a".repeat(1000)));
+	}
+
+	#[test]
+	fn stream_matcher() {
+		use std::io::BufReader;
+
+		let data: &[u8] = b"one=1;two=22;;three=333;";
+		let mut m = LuaPattern::new("(%a+)=(%d+)");
+		let expected: Vec<_> = m.gmatch_bytes(data).collect();
+		// tiny reads, so that matches straddle them
+		let reader = BufReader::with_capacity(3, data);
+		let found: Vec<_> = StreamMatcher::new(LuaPattern::new("(%a+)=(%d+)"), reader)
+			.max_match_len(10)
+			.map(|res| res.unwrap())
+			.collect();
+		assert_eq!(found.len(), 3);
+		for (w, e) in found.iter().zip(&expected) {
+			assert_eq!(w.get(1), *e);
+			assert_eq!(&data[w.range().start as usize..w.range().end as usize], w.bytes());
+		}
+		assert_eq!(found[2].capture(2), 20..23);
+		assert_eq!(found[2].get(2), b"333");
+
+		// long stretches without a match keep the window bounded
+		let data = [b"x".repeat(10000), b"1234".to_vec()].concat();
+		let mut sm = StreamMatcher::new(LuaPattern::new("%d+"), BufReader::with_capacity(64, &data[..]))
+			.max_match_len(8);
+		assert_eq!(sm.next().unwrap().unwrap().range(), 10000..10004);
+		assert!(sm.next().is_none());
+
+		// position captures are empty ranges where they are in the match
+		let mut sm = StreamMatcher::new(LuaPattern::new("()a()"), &b"xa"[..]);
+		let w = sm.next().unwrap().unwrap();
+		assert_eq!(w.capture(1), 1..1);
+		assert_eq!(w.capture(2), 2..2);
+		assert!(sm.next().is_none());
+		let mut sm = StreamMatcher::new(LuaPattern::new("(x)()(a)"), &b"yxa"[..]);
+		let w = sm.next().unwrap().unwrap();
+		assert_eq!(w.range(), 1..3);
+		assert_eq!(w.capture(1), 1..2);
+		assert_eq!(w.capture(2), 2..2);
+		assert_eq!(w.capture(3), 2..3);
+		// while `capture` still gives them one before, as it always has
+		let mut m = LuaPattern::new("()a()");
+		assert!(m.matches("xa"));
+		assert_eq!(m.capture(2), 1..1);
+	}
+
+	#[test]
+	fn partial_matching() {
+		let mut m = LuaPattern::new("^(%a+)=(%b\"\");");
+		assert_eq!(m.match_partial("key"), PartialMatch::Incomplete);
+		assert_eq!(m.match_partial("key=\"val"), PartialMatch::Incomplete);
+		assert_eq!(m.match_partial("key=\"val\""), PartialMatch::Incomplete);
+		assert_eq!(m.match_partial("key=\"val\";"), PartialMatch::Matched);
+		assert_eq!(m.captures("key=\"val\";"), &["key=\"val\";", "key", "\"val\""]);
+		assert_eq!(m.match_partial("key:"), PartialMatch::NoMatch);
+		assert_eq!(m.match_partial(""), PartialMatch::Incomplete);
+
+		// back references and frontiers can also run out of input
+		let mut m = LuaPattern::new("^(%a+):%1%f[%A]");
+		assert_eq!(m.match_partial("ab:a"), PartialMatch::Incomplete);
+		// like Lua, the end counts as '\0' for a frontier
+		assert_eq!(m.match_partial("ab:ab"), PartialMatch::Matched);
+		assert_eq!(m.match_partial("ab:ac"), PartialMatch::NoMatch);
+		assert_eq!(m.match_partial("ab:ab."), PartialMatch::Matched);
+
+		// unanchored patterns can start anywhere, including the end
+		let mut m = LuaPattern::new("abc");
+		assert_eq!(m.match_partial("xx"), PartialMatch::Incomplete);
+		assert_eq!(m.match_partial("xxabc"), PartialMatch::Matched);
+		assert_eq!(m.range(), 2..5);
+	}
+
+	#[test]
+	fn pattern_set() {
+		let patts = ["^GET (%S+)", "^POST", "(%d+)ms", "user=(%w+)", "%.php", "x?", "(a)%1"];
+		let mut set: LuaPatternSet = patts.iter().map(|p| LuaPattern::new(p)).collect();
+		assert_eq!(set.len(), 7);
+
+		// same answers as trying every pattern in turn
+		let lines = ["GET /index.php took 5ms", "POST user=bob", "", "aa", "nothing here"];
+		for line in lines.iter() {
+			let expected: Vec<_> = (0..patts.len())
+				.filter(|&i| LuaPattern::new(patts[i]).matches(line))
+				.collect();
+			assert_eq!(set.matches(line), expected);
+		}
+
+		assert_eq!(set.leftmost("took 5ms for user=bob"), Some(5));
+		assert_eq!(set.leftmost("user=bob took 5ms"), Some(3));
+		assert_eq!(set.get(2).match_captures("user=bob took 5ms").get(1), "5");
+		assert!(set.is_match("GET /"));
+
+		let mut set = LuaPatternSet::new([LuaPattern::new("^abc"), LuaPattern::new("xyz")]);
+		assert!(! set.is_match("zyx cba"));
+		assert_eq!(set.leftmost("abc"), Some(0));
+
+		// literals sharing a first byte are found in the same pass
+		let mut set = LuaPatternSet::new([LuaPattern::new("abd"), LuaPattern::new("abc"), LuaPattern::new("bc$")]);
+		assert_eq!(set.matches("abd abc"), &[0, 1, 2]);
+		assert_eq!(set.matches("abcd"), &[1]);
+		assert!(set.matches("ab").is_empty());
+	}
+
+	#[test]
+	fn leftmost_longest() {
+		let longest = |p| LuaPattern::new(p).with_match_kind(MatchKind::LeftmostLongest);
+
+		// Lua stops at the first alternative which works
+		let mut m = LuaPattern::new("a-b?");
+		assert!(m.matches("aab"));
+		assert_eq!(m.range(), 0..0);
+		let mut m = longest("a-b?");
+		assert!(m.matches("aab"));
+		assert_eq!(m.range(), 0..3);
+
+		// captures come from the longest path
+		let mut m = longest("(%d-)(%d?)x?");
+		assert_eq!(m.captures("12x3"), &["12x", "1", "2"]);
+		assert_eq!(LuaPattern::new("(%d-)(%d?)x?").captures("12x3"), &["1", "", "1"]);
+
+		// still the leftmost start, and still fails when nothing matches
+		let mut m = longest("%d+%.?%d*");
+		assert!(m.matches("v 1.25 and 300"));
+		assert_eq!(m.range(), 2..6);
+		assert!(! m.matches("none"));
+		assert_eq!(m.gmatch("1.5 22 3.").collect::<Vec<_>>(), &["1.5", "22", "3."]);
+	}
+
+	#[test]
+	fn parse_tree() {
+		use crate::ast::*;
+		use std::boxed::Box;
+
+		let digits = Item::Quantified{
+			item: Box::new(Item::Class{class: Class::Digit, negated: false}),
+			quantifier: Quantifier::OneOrMore
+		};
+		let items = LuaPattern::new("^(%a(%d+))()=%2%$%b{}.-$").parse();
+		assert_eq!(items, &[
+			Item::Start,
+			Item::Capture{index: 1, items: std::vec![
+				Item::Class{class: Class::Letter, negated: false},
+				Item::Capture{index: 2, items: std::vec![digits]},
+			]},
+			Item::PositionCapture{index: 3},
+			Item::Literal(b'='),
+			Item::BackRef(BackRefIndex::new(2).unwrap()),
+			Item::Literal(b'$'),
+			Item::Balance{open: b'{', close: b'}'},
+			Item::Quantified{item: Box::new(Item::Any), quantifier: Quantifier::Lazy},
+			Item::End,
+		]);
+
+		// sets follow matchbracketclass: a trailing '-' is literal
+		let items = LuaPattern::new("[^%S%]a-z-]*").parse();
+		assert_eq!(items, &[Item::Quantified{
+			item: Box::new(Item::Set(Set{
+				negated: true,
+				ranges: std::vec![(b']', b']'), (b'a', b'z'), (b'-', b'-')],
+				classes: std::vec![(Class::Space, true)]
+			})),
+			quantifier: Quantifier::ZeroOrMore
+		}]);
+
+		// '^' and '$' are only anchors at either end
+		assert_eq!(LuaPattern::new("a^$b").parse(), &[
+			Item::Literal(b'a'), Item::Literal(b'^'), Item::Literal(b'$'), Item::Literal(b'b')
+		]);
+
+		// escaped letters which are not classes match themselves
+		let mut m = LuaPattern::new("%Y");
+		assert_eq!(m.parse(), &[Item::Literal(b'Y')]);
+		assert!(m.matches("Y") && ! m.matches("y"));
+	}
+
+	#[test]
+	fn print_round_trip() {
+		use crate::ast::{parse, print, Item, Set};
+
+		// sets as the bytes they match, for those with empty ranges like
+		// "z-a", which are left out where they can't be written
+		fn same_bytes(items: &[Item]) -> std::vec::Vec<Item> {
+			let bytes_of = |set: &Set| {
+				let has = |c: u8| set.ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi)
+					|| set.classes.iter().any(|&(class, negated)| luapat::class_match(c, &[b'%', class.to_byte(negated)]));
+				Set{negated: set.negated, ranges: (0..=255).filter(|&c| has(c)).map(|c| (c, c)).collect(), classes: std::vec![]}
+			};
+			items.iter().map(|item| match item {
+				Item::Set(set) => Item::Set(bytes_of(set)),
+				Item::Frontier(set) => Item::Frontier(bytes_of(set)),
+				Item::Quantified{item, quantifier} => Item::Quantified{
+					item: std::boxed::Box::new(same_bytes(core::slice::from_ref(item)).remove(0)),
+					quantifier: *quantifier
+				},
+				Item::Capture{index, items} => Item::Capture{index: *index, items: same_bytes(items)},
+				other => other.clone()
+			}).collect()
+		}
+		fn has_empty_range(items: &[Item]) -> bool {
+			items.iter().any(|item| match item {
+				Item::Set(set) | Item::Frontier(set) => set.ranges.iter().any(|&(lo, hi)| lo > hi),
+				Item::Quantified{item, ..} => has_empty_range(core::slice::from_ref(item)),
+				Item::Capture{items, ..} => has_empty_range(items),
+				_ => false
+			})
+		}
+		let round_trip = |p: &[u8]| {
+			let items = parse(p).unwrap();
+			let printed = print(&items);
+			let again = parse(&printed);
+			let context = std::format!("{:?} -> {:?}", String::from_utf8_lossy(p), String::from_utf8_lossy(&printed));
+			if has_empty_range(&items) {
+				assert_eq!(again.map(|items| same_bytes(&items)), Ok(same_bytes(&items)), "{}", context);
+			} else {
+				assert_eq!(again, Ok(items), "{}", context);
+			}
+		};
+
+		// ranges are read as the matcher reads them
+		let items = parse(b"[!-%%]").unwrap();
+		assert_eq!(items, &[Item::Set(Set{negated: false, ranges: std::vec![(b'!', b'%'), (b']', b']')], classes: std::vec![]})]);
+
+		for p in ["[!-%%]", "^a.-%$$", "%^[%^^]%]", "a%-*-b", "[x--z][%.--z]", "()%b%%%f[%]-]%1",
+			"[!-%]-z]", "%f[^%]-~]", "[]-a]", "[$-%%a]", "[a-%x]"] {
+			round_trip(p.as_bytes());
+		}
+
+		// random patterns from magic characters and a few others
+		let alphabet = b"ab^$%()[]-*+?.dDfbx1y!";
+		let mut state = 0x2545_f491_4f6c_dd1d_u64;
+		let mut checked = 0;
+		for _ in 0..50_000 {
+			state ^= state << 13;
+			state ^= state >> 7;
+			state ^= state << 17;
+			let len = (state % 12) as usize;
+			let p: std::vec::Vec<u8> = (0..len)
+				.map(|i| alphabet[((state >> (5 * i + 4)) % alphabet.len() as u64) as usize])
+				.collect();
+			if parse(&p).is_ok() {
+				round_trip(&p);
+				checked += 1;
+			}
+		}
+		assert!(checked > 1000);
+	}
+
+	#[test]
+	fn explain_pattern() {
+		let lines = LuaPattern::new("(%a(()[^_%s]?))%2%b<>%f[^%l]").explain();
+		let got: std::vec::Vec<(usize, &str, &str)> = lines.iter()
+			.map(|l| (l.depth, l.fragment.as_str(), l.text.as_str()))
+			.collect();
+		assert_eq!(got, &[
+			(0, "(", "capture #1 begins"),
+			(1, "%a", "match a letter"),
+			(1, "(", "capture #2 begins"),
+			(2, "()", "capture #3: the current position"),
+			(2, "[^%s_]?", "optionally match a byte matching none of: a whitespace character, '_', once or not at all"),
+			(1, ")", "capture #2 ends"),
+			(0, ")", "capture #1 ends"),
+			(0, "%2", "match the text of capture #2 again"),
+			(0, "%b<>", "match text balanced between '<' and '>'"),
+			(0, "%f[^%l]", "frontier: transition from lowercase letter to non-lowercase letter"),
+		]);
+		assert_eq!(lines[2].to_string(), "  (: capture #2 begins");
+	}
+
+	#[test]
+	fn lint_pattern() {
+		use crate::lint::{lint, lint_with_template, WarningKind::*};
+
+		let found = |p: &str| -> std::vec::Vec<_> {
+			lint(p.as_bytes()).unwrap().into_iter().map(|w| (w.kind, w.span)).collect()
+		};
+		assert_eq!(found("%y[%z_]%f[a-]"), &[(NeedlessEscape, 0..2), (NeedlessEscape, 3..5), (TrailingDash, 10..12)]);
+		assert_eq!(found("key=(.*)$"), &[(RedundantEnd, 5..9)]);
+		assert_eq!(found("x-y%s-[a-]-"), &[(LazyDash, 0..2), (TrailingDash, 7..9)]);
+		// these are all fine
+		assert!(found("^.-$[-a][a%-][%a-]%.*$(%b())%1").is_empty());
+		assert!(lint(b"(oops").is_err());
+
+		// captures used by back references or the template
+		let unused: std::vec::Vec<_> = lint_with_template(b"(%a)()(%d)%1", "%0 %%3").unwrap()
+			.into_iter().map(|w| (w.kind, w.span)).collect();
+		assert_eq!(unused, &[(UnusedCapture(2), 4..6), (UnusedCapture(3), 6..10)]);
+	}
+
+	#[test]
+	fn to_regex() {
+		let regex = |p: &str| LuaPattern::from_bytes(p.as_bytes()).to_regex();
+		assert_eq!(regex("a.b*c-d?e+$x$").unwrap(), r"(?s-u)a.b*c*?d?e+\$x$");
+		assert_eq!(regex("[%a_][^%D%-]%W%p[]").unwrap(),
+			r"(?s-u)[[:alpha:]_][^\D\-][[:^alnum:]][[:punct:]][^\x00-\xFF]");
+		let bytes = LuaPattern::from_bytes(b"\x01\xFF[\x00-\x7F]").to_regex();
+		assert_eq!(bytes.unwrap(), r"(?s-u)\x01\xFF[\x00-\x7F]");
+		assert_eq!(regex("(a)%1"), Err(RegexError::BackRef));
+		assert_eq!(regex("%f[%a]"), Err(RegexError::Frontier));
+
+		// Lua sees '\0' before and after the text, which lookaround doesn't
+		let options = RegexOptions{lookaround: true};
+		let m = LuaPattern::new("%f[%c%s]");
+		assert_eq!(m.to_regex_with(options).unwrap(),
+			r"(?s-u)(?<=[^[:cntrl:][\t\n\x0C\r ]])(?![^[:cntrl:][\t\n\x0C\r ]])");
+		let m = LuaPattern::new("%f[^%a]");
+		assert_eq!(m.to_regex_with(options).unwrap(), r"(?s-u)(?<=[[:alpha:]])(?![[:alpha:]])");
+	}
+
+	#[test]
+	fn regex_classes() {
+		// the bytes matched by one item of a regex, with the POSIX classes
+		// as the regex crate documents them
+		fn item(re: &[u8], i: &mut usize) -> [bool; 256] {
+			let mut has = [false; 256];
+			let mut add = |lo: u8, hi: u8| (lo..=hi).for_each(|c| has[c as usize] = true);
+			let c = re[*i];
+			*i += 1;
+			if c == b'[' && re[*i] == b':' {
+				let end = *i + re[*i..].iter().position(|&c| c == b']').unwrap();
+				let (negated, name) = match &re[*i + 1..end - 1] {
+					[b'^', name @ ..] => (true, name),
+					name => (false, name)
+				};
+				let ranges: &[(u8, u8)] = match name {
+					b"alnum" => &[(b'0', b'9'), (b'A', b'Z'), (b'a', b'z')],
+					b"alpha" => &[(b'A', b'Z'), (b'a', b'z')],
+					b"cntrl" => &[(0, 0x1F), (0x7F, 0x7F)],
+					b"graph" => &[(b'!', b'~')],
+					b"lower" => &[(b'a', b'z')],
+					b"punct" => &[(b'!', b'/'), (b':', b'@'), (b'[', b'`'), (b'{', b'~')],
+					b"upper" => &[(b'A', b'Z')],
+					b"xdigit" => &[(b'0', b'9'), (b'A', b'F'), (b'a', b'f')],
+					_ => panic!("unknown class {:?}", name)
+				};
+				ranges.iter().for_each(|&(lo, hi)| add(lo, hi));
+				*i = end + 1;
+				if negated {
+					has.iter_mut().for_each(|h| *h = ! *h);
+				}
+			} else if c == b'[' {
+				let negated = re[*i] == b'^';
+				if negated {
+					*i += 1;
+				}
+				while re[*i] != b']' {
+					let inner = item(re, i);
+					(0..=255).filter(|&c| inner[c as usize]).for_each(|c| add(c, c));
+				}
+				*i += 1;
+				if negated {
+					has.iter_mut().for_each(|h| *h = ! *h);
+				}
+			} else if c == b'\\' {
+				*i += 1;
+				match re[*i - 1] {
+					b'd' => add(b'0', b'9'),
+					b'D' => {
+						add(0, b'0' - 1);
+						add(b'9' + 1, 255);
+					},
+					b't' => add(b'\t', b'\t'),
+					b'n' => add(b'\n', b'\n'),
+					b'r' => add(b'\r', b'\r'),
+					b'x' => {
+						let hex = u8::from_str_radix(core::str::from_utf8(&re[*i..*i + 2]).unwrap(), 16).unwrap();
+						add(hex, hex);
+						*i += 2;
+					},
+					escaped => add(escaped, escaped)
+				}
+			} else {
+				add(c, c);
+			}
+			has
+		}
+
+		for class in b"acdglpsuwxACDGLPSUWX" {
+			let alone = [b'%', *class];
+			let in_set = [b'[', b'%', *class, b']'];
+			for p in [&alone[..], &in_set[..]] {
+				let re = LuaPattern::from_bytes(p).to_regex().unwrap();
+				let re = re.strip_prefix("(?s-u)").unwrap().as_bytes();
+				let mut i = 0;
+				let has = item(re, &mut i);
+				assert_eq!(i, re.len(), "{:?}", re);
+				for c in 0..=255u8 {
+					assert_eq!(has[c as usize], luapat::class_match(c, &alone), "{:?} {}", String::from_utf8_lossy(p), c);
+				}
+			}
+		}
+	}
+
+	#[test]
+	fn regex_to_pattern() {
+		use crate::errors::FromRegexError::*;
+
+		let convert = |re: &str| LuaPatternBuilder::new().regex(re).map(|b| String::from_utf8(b.build()).unwrap());
+		assert_eq!(convert(r"^\d{3}-\w+$").unwrap(), "^%d%d%d%-[%w_]+$");
+		assert_eq!(convert(r"a{2,4}b{2,}c*?d+?\.(?:x)").unwrap(), "aaa?a?bb+c-dd-%.x");
+		assert_eq!(convert(r"[^\]\-a-f\d[:upper:]]\x41\W\S").unwrap(), "[^%d%u%]%-a-f]A[^%w_][^%s\x0B]");
+		assert_eq!(convert("é?\\s").unwrap_err(), GroupQuantifier(2));
+		assert_eq!(convert("a(b|c)").unwrap_err(), Alternation(3));
+		assert_eq!(convert("(?:ab)*").unwrap_err(), GroupQuantifier(6));
+		assert_eq!(convert(r"\s[\s[:space:]]").unwrap(), "[%s\x0B][%s%s\x0B\x0B]");
+		assert_eq!(convert(r"[a\S]").unwrap_err(), Unsupported(2));
+		assert_eq!(convert(r"[^[:^space:]]").unwrap_err(), Unsupported(2));
+		assert_eq!(convert(r"\bword").unwrap_err(), Unsupported(0));
+		assert_eq!(convert("a^").unwrap_err(), Unsupported(1));
+		assert_eq!(convert("a??").unwrap_err(), Unsupported(1));
+		assert_eq!(convert("[a-").unwrap_err(), Syntax(0));
+		assert_eq!(convert("(a").unwrap_err(), Syntax(0));
+		assert_eq!(convert("x{3,1}").unwrap_err(), Syntax(1));
+		assert_eq!(convert("a{100000000}").unwrap_err(), TooManyRepeats(1));
+		assert_eq!(convert("a{2,1001}").unwrap_err(), TooManyRepeats(1));
+		assert!(convert("a{1000,}").is_ok());
+
+		// the result is a valid pattern that matches like the regex
+		let patt = convert(r"(\w+)@(\w+)\.com").unwrap();
+		let mut m = LuaPattern::new(&patt);
+		assert_eq!(m.captures("mail: joe_b@example.com"), &["joe_b@example.com", "joe_b", "example"]);
+	}
+
+	#[test]
+	fn glob_to_pattern() {
+		let matches = |glob: &str, path: &str| LuaPattern::from_bytes(&glob_pattern(glob)).matches(path);
+		assert!(matches("*.rs", "lib.rs"));
+		assert!(! matches("*.rs", "src/lib.rs"));
+		assert!(! matches("*.rs", "lib.rs.bak"));
+		assert!(matches("src/?ib.rs", "src/lib.rs"));
+		assert!(! matches("?", "/"));
+
+		// '**' as a component spans directories, elsewhere it is '*'
+		assert!(matches("**/*.rs", "lib.rs"));
+		assert!(matches("**/*.rs", "src/a/lib.rs"));
+		assert!(matches("src/**/lib.rs", "src/lib.rs"));
+		assert!(matches("src/**/lib.rs", "src/a/b/lib.rs"));
+		assert!(! matches("src/**/lib.rs", "src/alib.rs"));
+		assert!(! matches("src/**/lib.rs", "xsrc/lib.rs"));
+		assert!(matches("target/**", "target/debug/build"));
+		assert!(! matches("target/**", "target"));
+		assert!(matches("**", "a/b"));
+		assert!(matches("a**b", "axxb"));
+		assert!(! matches("a**b", "a/b"));
+
+		// sets, escapes and literal magic
+		assert!(matches("[abc].txt", "b.txt"));
+		assert!(matches("[!abc].txt", "d.txt"));
+		assert!(! matches("[^abc].txt", "a.txt"));
+		assert!(! matches("a[!x]b", "a/b"));
+		assert!(matches("[]-]", "]"));
+		assert!(matches("[]-]", "-"));
+		assert!(matches("[0-9][0-9]%", "42%"));
+		assert!(matches(r"\*.(txt)", "*.(txt)"));
+		assert!(! matches(r"\*", "x"));
+		assert!(matches("a[b", "a[b"));
+		assert!(matches("^$.+", "^$.+"));
+
+		// sets are of bytes, and a backwards range matches nothing
+		assert_eq!(glob_pattern("[é-%]"), b"^[\xC3]$");
+		assert!(LuaPattern::from_bytes(&glob_pattern("x[é]")).matches_bytes(b"x\xA9"));
+		assert!(! matches("[z-a]", "m"));
+		assert!(! matches("[%-!]", "%"));
+	}
+
+	#[test]
+	fn builder_combinators() {
+		use crate::ast::{Class, Set};
+		// key = "value", with the quote matched again
+		let patt = LuaPatternBuilder::new()
+			.start()
+			.class(Class::Space).zero_or_more()
+			.capture(|b| b.set(|s| s.class(Class::AlphaNum).byte(b'_').byte(b'-')).one_or_more())
+			.class(Class::Space).zero_or_more()
+			.bytes(b"=")
+			.class(Class::Space).zero_or_more()
+			.capture(|b| b.set(|s| s.byte(b'"').byte(b'\'')))
+			.capture(|b| b.any().lazy())
+			.backref(2)
+			.position()
+			.end()
+			.build();
+		assert_eq!(patt, b"^%s*([%w_%-]+)%s*=%s*([\"'])(.-)%2()$");
+		let mut m = LuaPattern::from_bytes(&patt);
+		assert_eq!(m.captures(" my-key = 'it\"s'"), &[" my-key = 'it\"s'", "my-key", "'", "it\"s", ""]);
+		assert!(! m.matches("key = 'mismatched\""));
+
+		// magic bytes are escaped and quantifiers apply to the last byte
+		let patt = LuaPatternBuilder::new()
+			.bytes(b"1+1").optional()
+			.frontier(Set::default().range(b'0', b'9').byte(b']').negate())
+			.balanced(b'[', b']')
+			.build();
+		assert_eq!(patt, b"1%+1?%f[^0-9%]]%b[]");
+		let mut m = LuaPattern::from_bytes(&patt);
+		assert_eq!(m.match_maybe("x 1+1[a[b]]"), Some("1+1[a[b]]"));
+		assert_eq!(m.match_maybe("1+[x]"), None);
+		assert!(std::panic::catch_unwind(|| LuaPatternBuilder::new().text("(").one_or_more().build()).is_err());
+	}
+
+	#[test]
+	fn builder_try_build() {
+		use crate::ast::Set;
+		use crate::errors::{BuildError, BuildErrorKind};
+		let mut b = LuaPatternBuilder::new();
+		b.text("(").text("a)").text("(").bytes(b"b");
+		assert_eq!(b.capture_count(), 2);
+		assert_eq!(b.try_build(), Err(BuildError{step: 2, error: PatternError::UnfinishedCapture.into()}));
+		assert_eq!(b.try_build().unwrap_err().to_string(), "builder step 2: unfinished capture");
+		b.text(")");
+		assert_eq!(b.try_build().unwrap(), b"(a)(b)");
+
+		// a '%' split across calls is fine
+		let mut b = LuaPatternBuilder::new();
+		b.text("%").text("d").capture(|b| b.any()).text("%2");
+		assert_eq!(b.try_build(), Err(BuildError{step: 4, error: PatternError::InvalidCaptureIndex(Some(1)).into()}));
+		let mut b = LuaPatternBuilder::new();
+		b.bytes(b"(").text(")");
+		assert_eq!(b.capture_count(), 0);
+		assert_eq!(b.try_build(), Err(BuildError{step: 1, error: PatternError::NoOpenCapture.into()}));
+		assert_eq!(LuaPatternBuilder::new().try_build().unwrap(), b"");
+
+		// "[]" is an empty set, so the '(' after it opens a capture
+		let mut b = LuaPatternBuilder::new();
+		b.text("[](");
+		assert_eq!(b.capture_count(), 1);
+		assert_eq!(b.try_build(), Err(BuildError{step: 0, error: PatternError::UnfinishedCapture.into()}));
+
+		// calls which can't add to the pattern are errors, and can't be fixed later
+		let misuse = |f: &dyn Fn(&mut LuaPatternBuilder) -> &mut LuaPatternBuilder| {
+			let mut b = LuaPatternBuilder::new();
+			let err = f(b.text("a")).text("b").try_build().unwrap_err();
+			assert_eq!(b.text(")").try_build(), Err(err));
+			err
+		};
+		assert_eq!(misuse(&|b| b.set(|s| s)), BuildError{step: 1, error: BuildErrorKind::EmptySet});
+		assert_eq!(misuse(&|b| b.frontier(Set::default())), BuildError{step: 1, error: BuildErrorKind::EmptySet});
+		assert_eq!(misuse(&|b| b.text("%d").one_or_more()), BuildError{step: 2, error: BuildErrorKind::NothingToRepeat});
+		assert_eq!(misuse(&|b| b.any().optional().lazy()), BuildError{step: 3, error: BuildErrorKind::NothingToRepeat});
+		assert_eq!(misuse(&|b| b.capture(|b| b)), BuildError{step: 1, error: BuildErrorKind::EmptyCapture});
+		assert_eq!(misuse(&|b| b.capture(|b| b.zero_or_more())), BuildError{step: 2, error: BuildErrorKind::NothingToRepeat});
+		assert_eq!(misuse(&|b| b.backref(10)), BuildError{step: 1, error: BuildErrorKind::InvalidBackRef(10)});
+		assert_eq!(misuse(&|b| b.start()), BuildError{step: 1, error: BuildErrorKind::MisplacedStart});
+		// the earlier of a misuse and an invalid pattern is reported
+		let mut b = LuaPatternBuilder::new();
+		b.text("(").backref(0);
+		assert_eq!(b.try_build(), Err(BuildError{step: 0, error: PatternError::UnfinishedCapture.into()}));
+		let mut b = LuaPatternBuilder::new();
+		b.backref(0).text("(");
+		let err = b.try_build().unwrap_err();
+		assert_eq!(err, BuildError{step: 0, error: BuildErrorKind::InvalidBackRef(0)});
+		assert_eq!(err.to_string(), "builder step 0: back reference %0 out of range");
+	}
+
+	#[test]
+	fn template_errors() {
+		use crate::errors::TemplateError;
+		let mut m = LuaPattern::new("(%w+)()");
+		assert_eq!(m.info().captures, 2);
+		assert_eq!(m.try_gsub("hi there", "[%1]").unwrap(), "[hi] [there]");
+		assert_eq!(m.try_gsub("hi", "%0%%%0").unwrap(), "hi%hi");
+		assert_eq!(m.try_gsub("hi", "%1 %3"), Err(TemplateError::InvalidCaptureIndex(3, 3)));
+		let err = m.try_gsub("hi", "50% off").unwrap_err();
+		assert_eq!(err, TemplateError::InvalidEscape(2));
+		assert_eq!(err.position(), 2);
+		assert_eq!(err.to_string(), "invalid use of '%' in replacement string at 2");
+		assert_eq!(m.try_gsub("hi", "%"), Err(TemplateError::InvalidEscape(0)));
+
+		// without captures, %1 is the whole match but %2 is still wrong
+		let mut m = LuaPattern::new("%d");
+		assert_eq!(m.try_gsub("a1b2", "<%1>").unwrap(), "a<1>b<2>");
+		assert_eq!(m.try_gsub("a1b2", "%2"), Err(TemplateError::InvalidCaptureIndex(0, 2)));
+		assert!(Substitute::try_new("%9").is_ok());
+		assert!(Substitute::try_new("%x").is_err());
+
+		// a parsed template is checked against the pattern it is used with
+		let s = Substitute::try_new("<%1>").unwrap();
+		assert!(m.matches("a12"));
+		assert_eq!(s.subst(&m, "a12"), "<1>");
+		let mut m = LuaPattern::new("(%a+)(%d)");
+		assert!(m.matches("abcdefgh1"));
+		assert!(m.matches("x1"));
+		let s = Substitute::try_new("%2%7").unwrap();
+		assert_eq!(s.try_subst(&m, "x1"), Err(TemplateError::InvalidCaptureIndex(2, 7)));
+		let mut out = String::from("kept");
+		let err = s.expand_into(&m.match_captures("x1"), &mut out);
+		assert_eq!(err, Err(TemplateError::InvalidCaptureIndex(2, 7)));
+		assert_eq!(out, "kept");
+		assert!(std::panic::catch_unwind(|| s.subst(&m, "x1")).is_err());
+		let s = Substitute::extended("%{2:pad3}|%U1").unwrap();
+		assert_eq!(s.try_subst(&m, "x1").unwrap(), "1  |X");
+	}
+
+	#[test]
+	fn gsub_bytes_template() {
+		// swap the two bytes after each 0xFF marker, keeping the marker
+		let mut m = LuaPattern::from_bytes(b"\xFF(.)(.)");
+		let res = m.gsub_bytes(&[0x00, 0xFF, 0x01, 0x02, 0xFF, 0xFF, 0x80, 0x03], b"%0|%2%1");
+		assert_eq!(res, &[0x00, 0xFF, 0x01, 0x02, b'|', 0x02, 0x01, 0xFF, 0xFF, 0x80, b'|', 0x80, 0xFF, 0x03]);
+
+		// the template may hold any bytes, and the string version agrees
+		let mut m = LuaPattern::new("(%a+)");
+		assert_eq!(m.gsub_bytes(b"ab cd", b"\xFE%1%%"), b"\xFEab% \xFEcd%");
+		assert_eq!(m.gsub_bytes(b"ab cd", b"<%1>%x"), m.gsub("ab cd", "<%1>%x").as_bytes());
+	}
+
+	#[test]
+	fn gsub_max_count() {
+		let mut m = LuaPattern::new("(%w+)@%w+%.com");
+		let text = "from a@b.com to c@d.com, cc e@f.com";
+		assert_eq!(m.gsub_n(text, "%1@***", 1), ("from a@*** to c@d.com, cc e@f.com".into(), 1));
+		assert_eq!(m.gsub_n(text, "%1@***", 0), (text.into(), 0));
+		assert_eq!(m.gsub_n(text, "%1@***", 10).1, 3);
+		let (res, n) = m.gsub_with_n(text, |cc| cc.get(1).to_uppercase(), 2);
+		assert_eq!(res, "from A to C, cc e@f.com");
+		assert_eq!(n, 2);
+		assert_eq!(m.gsub_bytes_n(text.as_bytes(), b"-", 2), (b"from - to -, cc e@f.com".to_vec(), 2));
+		let (res, n) = m.gsub_bytes_with_n(text.as_bytes(), |cc| cc.get(1).to_vec(), usize::MAX);
+		assert_eq!(res, b"from a to c, cc e");
+		assert_eq!(n, 3);
+		assert_eq!(LuaPattern::new("x").gsub_n("abc", "y", usize::MAX), ("abc".into(), 0));
+
+		// as in Lua, an empty match keeps the next byte, or character of a str
+		let mut m = LuaPattern::new("x*");
+		assert_eq!(m.gsub("abc", "-"), "-a-b-c-");
+		assert_eq!(m.gsub_n("abc", "-", 2), ("-a-bc".into(), 2));
+		// Lua 5.2 also takes the empty match straight after "xx"
+		assert_eq!(m.gsub("axxb", "-"), "-a--b-");
+		assert_eq!(m.gsub("é", "-"), "-é-");
+		assert_eq!(m.gsub_with_indexed("ab", |_, i, offset| std::format!("{}{}", i, offset)), "00a11b22");
+		assert_eq!(m.gsub_bytes(b"\xC3\xA9", b"-"), b"-\xC3-\xA9-");
+		let mut out = String::new();
+		m.gsub_into("ab", "<%0>", &mut out).unwrap();
+		assert_eq!(out, "<>a<>b<>");
+		// and an anchored pattern only matches once
+		assert_eq!(LuaPattern::new("^%s*").gsub("  a  b", ""), "a  b");
+		assert_eq!(LuaPattern::new("^a").gsub("aaa", "b"), "baa");
+	}
+
+	#[test]
+	fn gsub_maybe() {
+		use std::borrow::Cow;
+		use std::collections::HashMap;
+		let vars: HashMap<&str, &str> = [("home", "/home/joe"), ("shell", "bash")].into_iter().collect();
+		let mut m = LuaPattern::new("%$(%w+)");
+		let mut calls = 0;
+		let res = m.gsub_with_maybe("$home uses $shell, not $editor", |cc| {
+			calls += 1;
+			vars.get(cc.get(1)).map(|&v| Cow::Borrowed(v))
+		});
+		assert_eq!(res, "/home/joe uses bash, not $editor");
+		assert_eq!(calls, 3);
+
+		// replacements may borrow from the text, or be owned
+		let res = m.gsub_with_maybe("$a $bb", |cc| {
+			let name = cc.get(1);
+			if name.len() > 1 {Some(Cow::Owned(name.to_uppercase()))} else {Some(Cow::Borrowed(name))}
+		});
+		assert_eq!(res, "a BB");
+		let res = m.gsub_bytes_with_maybe(b"$x=$y", |cc| {
+			if cc.get(1) == b"y" {Some(Cow::Borrowed(cc.get(0)))} else {None}
+		});
+		assert_eq!(res, b"$x=$y");
+		let res = m.gsub_bytes_with_maybe(b"$x=$y", |cc| Some(Cow::Borrowed(cc.get(1))));
+		assert_eq!(res, b"x=y");
+	}
+
+	#[test]
+	fn gsub_lookup() {
+		use std::collections::{BTreeMap, HashMap};
+		use std::string::ToString;
+		let text = "$user is in $dir";
+		let mut m = LuaPattern::new("%$(%w+)");
+		let mut hash = HashMap::new();
+		hash.insert("user".to_string(), "joe".to_string());
+		assert_eq!(m.gsub_map(text, &hash), "joe is in $dir");
+		let tree: BTreeMap<&str, &str> = [("dir", "/tmp")].into_iter().collect();
+		assert_eq!(m.gsub_map(text, &tree), "$user is in /tmp");
+		let pairs = std::vec![("dir", "~"), ("user", "me")];
+		assert_eq!(m.gsub_map(text, pairs.as_slice()), "me is in ~");
+		assert_eq!(m.gsub_map(text, &|key: &str| Some(key.to_uppercase())), "USER is in DIR");
+
+		// without captures the key is the whole match
+		let mut m = LuaPattern::new("%a+");
+		assert_eq!(m.gsub_map("no yes", &[("yes", "ja")]), "no ja");
+	}
+
+	#[test]
+	fn gsub_into_sink() {
+		// a fixed buffer, so no allocation
+		struct Buf {
+			bytes: [u8; 16],
+			len: usize,
+		}
+		impl core::fmt::Write for Buf {
+			fn write_str(&mut self, s: &str) -> core::fmt::Result {
+				let end = self.len + s.len();
+				if end > self.bytes.len() {
+					return Err(core::fmt::Error);
+				}
+				self.bytes[self.len..end].copy_from_slice(s.as_bytes());
+				self.len = end;
+				Ok(())
+			}
+		}
+		let mut m = LuaPattern::new("(%d+)");
+		let mut buf = Buf{bytes: [0; 16], len: 0};
+		m.gsub_into("a1 b22", "<%1>", &mut buf).unwrap();
+		assert_eq!(&buf.bytes[..buf.len], b"a<1> b<22>");
+		let mut buf = Buf{bytes: [0; 16], len: 0};
+		assert!(m.gsub_into("1 2 3 4 5 6", "[%0]", &mut buf).is_err());
+		let mut s = String::new();
+		m.gsub_into("a1 b22", "%1%%%x", &mut s).unwrap();
+		assert_eq!(s, m.gsub("a1 b22", "%1%%%x"));
+
+		let mut out = std::io::Cursor::new(Vec::new());
+		m.gsub_bytes_into(b"x9y", b"(%0)", &mut out).unwrap();
+		assert_eq!(out.into_inner(), b"x(9)y");
+	}
+
+	#[test]
+	fn gsub_cow_borrows() {
+		use std::borrow::Cow;
+		let mut m = LuaPattern::new("<(%a+)>");
+		assert!(matches!(m.gsub_cow("no tags", "[%1]"), Cow::Borrowed("no tags")));
+		assert!(matches!(m.gsub_cow("<b>bold</b>", "[%1]"), Cow::Owned(_)));
+		assert_eq!(m.gsub_cow("<b>bold</b>", "[%1]"), "[b]bold</b>");
+		assert!(matches!(m.gsub_bytes_cow(b"x", b""), Cow::Borrowed(b"x")));
+		assert_eq!(m.gsub_bytes_cow(b"<i>x", b"").as_ref(), b"x");
+		assert!(matches!(m.gsub_with_cow("", |_| String::new()), Cow::Borrowed("")));
+		assert_eq!(m.gsub_with_cow("<i>", |cc| cc.get(1).into()), "i");
+		assert_eq!(m.gsub_bytes_with_cow(b"<i>", |cc| cc.get(1).into()).as_ref(), b"i");
+	}
+
+	#[test]
+	fn gsub_fnmut() {
+		// closures may change what they capture
+		let mut m = LuaPattern::new("%d+");
+		let mut total = 0;
+		let res = m.gsub_with("1 + 22", |cc| {
+			total += cc.get(0).len();
+			"#".into()
+		});
+		assert_eq!((res.as_str(), total), ("# + #", 3));
+		let mut seen = std::vec::Vec::new();
+		m.gsub_bytes_with(b"7x8", |cc| {
+			seen.push(cc.get(0)[0]);
+			std::vec::Vec::new()
+		});
+		assert_eq!(seen, b"78");
+
+		// offsets are in the whole text, not what is left of it
+		let mut m = LuaPattern::new("(%a)%a*");
+		let mut found = std::vec::Vec::new();
+		let res = m.gsub_with_indexed("see  the cat", |cc, i, offset| {
+			found.push((i, offset));
+			cc.get(1).to_uppercase()
+		});
+		assert_eq!(res, "S  T C");
+		assert_eq!(found, &[(0, 0), (1, 5), (2, 9)]);
+		let res = m.gsub_bytes_with_indexed(b"ab cd", |_, i, offset| std::vec![b'0' + i as u8, b'0' + offset as u8]);
+		assert_eq!(res, b"00 13");
+	}
+
+	#[test]
+	fn substitute_templates() {
+		let quote = Substitute::new("'%1'");
+		let mut m = LuaPattern::new("%$(%w+)");
+		assert_eq!(m.gsub_template("$a and $b", &quote), "'a' and 'b'");
+		assert_eq!(m.gsub_template("none", &quote), "none");
+		assert_eq!(m.gsub_template("$x", &quote), m.gsub("$x", "'%1'"));
+
+		// one template, several patterns
+		let mut out = String::new();
+		for patt in ["(%d+)", "(%a+)"] {
+			let mut m = LuaPattern::new(patt);
+			for cc in m.gmatch_captures("ab 12") {
+				quote.expand_with(|i| cc.get(i), &mut out);
+			}
+		}
+		assert_eq!(out, "'12''ab'");
+
+		// fixed capacity, with the output written to a heapless string
+		#[cfg(feature = "heapless")]
+		{
+			let quote = HeaplessSubstitute::<3>::new("'%1'").unwrap();
+			let mut out: heapless::String<8> = heapless::String::new();
+			m.gsub_template_into("$a $b", &quote, &mut out).unwrap();
+			assert_eq!(out, "'a' 'b'");
+			let mut out: heapless::String<4> = heapless::String::new();
+			assert!(m.gsub_template_into("$a $b", &quote, &mut out).is_err());
+		}
+	}
+
+	#[test]
+	fn extended_templates() {
+		use crate::errors::TemplateError;
+		// snake_case to SCREAMING_SNAKE and back
+		let mut m = LuaPattern::new("(%a+)_(%a+)");
+		assert_eq!(m.gsub_extended("max_len", "%U1_%U2").unwrap(), "MAX_LEN");
+		assert_eq!(m.gsub_extended("MAX_LEN", "%{1:lower}_%L2").unwrap(), "max_len");
+		assert_eq!(m.gsub_extended("a_b", "[%{0:pad5}][%{2:upper:pad3}]").unwrap(), "[a_b  ][B  ]");
+		assert_eq!(m.gsub_extended("long_name", "%{1:pad2}").unwrap(), "long");
+		assert_eq!(LuaPattern::new("_(.+)").gsub_extended("x_ÄÖ", "%L1").unwrap(), "xäö");
+
+		// plain Lua templates mean the same
+		for repl in ["%2 %1", "100%% %0", ""] {
+			assert_eq!(m.gsub_extended("a_b c_d", repl).unwrap(), m.gsub("a_b c_d", repl));
+		}
+
+		let err = |repl| Substitute::extended(repl).unwrap_err();
+		assert_eq!(err("x %U"), TemplateError::InvalidDirective(2));
+		assert_eq!(err("%Ux"), TemplateError::InvalidDirective(0));
+		assert_eq!(err("%{1:upper"), TemplateError::InvalidDirective(0));
+		assert_eq!(err("%{:upper}"), TemplateError::InvalidDirective(0));
+		assert_eq!(err("%{1:pad}"), TemplateError::InvalidDirective(0));
+		assert_eq!(err("ab%{1:pad256}"), TemplateError::InvalidDirective(2));
+		assert_eq!(err("%{1:pad99999999999999999999}"), TemplateError::InvalidDirective(0));
+		assert_eq!(m.gsub_extended("a_b", "%{1:pad255}").unwrap().len(), 255);
+		assert_eq!(err("%{1:title}").to_string(), "invalid directive in replacement string at 0");
+		assert_eq!(err("%x"), TemplateError::InvalidEscape(0));
+		assert_eq!(m.gsub_extended("a_b", "%{3}"), Err(TemplateError::InvalidCaptureIndex(0, 3)));
+		assert_eq!(err("x%{99}"), TemplateError::InvalidCaptureIndex(1, 99));
+		assert_eq!(Substitute::extended("%{9}").unwrap(), Substitute::new("%9"));
+	}
+
 	#[test]
 	fn bad_patterns() {
 	   let bad = [
-			( "bonzo %",              PatternError::MalformedPattern("ends with '%'") ),
-			( "bonzo (dog%(",         PatternError::UnfinishedCapture                 ),
-			( "alles [%a%[",          PatternError::MalformedPattern("missing ']'")   ),
-			( "bonzo (dog (cat)",     PatternError::UnfinishedCapture                 ),
-			( "frodo %f[%A",          PatternError::MalformedPattern("missing ']'")   ),
-			( "frodo (1) (2(3)%2)%1", PatternError::InvalidCaptureIndex(Some(1))      ),
+			( "bonzo %",              PatternError::EndsWithEscape               ),
+			( "bonzo (dog%(",         PatternError::UnfinishedCapture            ),
+			( "alles [%a%[",          PatternError::UnfinishedCharClass          ),
+			( "bonzo (dog (cat)",     PatternError::UnfinishedCapture            ),
+			( "frodo %f[%A",          PatternError::UnfinishedCharClass          ),
+			( "frodo (1) (2(3)%2)%1", PatternError::InvalidCaptureIndex(Some(1)) ),
 		];
 		for p in bad.iter() {
 			let res = LuaPattern::new_try(p.0);
@@ -0,0 +1,171 @@
+//! Warnings for patterns which are valid, but probably not what was meant.
+//!
+//! ```
+//! use lua_patterns2::lint::{lint, WarningKind};
+//!
+//! let warnings = lint(b"[a-] foo-bar").unwrap();
+//! assert_eq!(warnings.len(), 2);
+//! assert_eq!(warnings[0].kind, WarningKind::TrailingDash);
+//! assert_eq!(warnings[0].span, 1..3);
+//! assert_eq!(warnings[1].kind, WarningKind::LazyDash);
+//! assert_eq!(warnings[1].span, 7..9);
+//! assert!(lint(b"[a-z]+ foo%-bar").unwrap().is_empty());
+//! ```
+
+use core::fmt;
+use core::ops;
+use std::vec::Vec;
+
+use crate::ast::Class;
+use crate::errors::PatternError;
+use crate::info::{Item, Items};
+use crate::luapat::str_check;
+use crate::subst::{generate_gsub_patterns, Subst};
+
+/// What a warning is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind {
+	/// `[a-]`: the `-` is a literal, not the start of a range
+	TrailingDash,
+	/// `%y`: a letter or digit which is not a class only matches itself
+	NeedlessEscape,
+	/// `.*$`: the `.*` already matches to the end
+	RedundantEnd,
+	/// `foo-bar`: the `-` repeats the `o` lazily
+	LazyDash,
+	/// A capture which neither the pattern nor the template refers to
+	UnusedCapture(usize),
+}
+
+/// A suspicious part of a pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+	/// What is wrong
+	pub kind: WarningKind,
+	/// Where it is in the pattern
+	pub span: ops::Range<usize>,
+}
+
+impl fmt::Display for Warning {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}..{}: ", self.span.start, self.span.end)?;
+		match self.kind {
+			WarningKind::TrailingDash => write!(f, "'-' at the end of a set is a literal, not a range"),
+			WarningKind::NeedlessEscape => write!(f, "'%' before a letter or digit which is not a class just matches that character"),
+			WarningKind::RedundantEnd => write!(f, "'$' after '.*' is redundant, as '.*' already matches to the end"),
+			WarningKind::LazyDash => write!(f, "'-' after a character repeats it lazily; use '%-' to match a dash"),
+			WarningKind::UnusedCapture(i) => write!(f, "capture #{} is never referenced", i),
+		}
+	}
+}
+
+fn needless_escape(c: u8) -> bool {
+	c.is_ascii_alphanumeric() && Class::from_byte(c).is_none()
+}
+
+// the set p[start..end], as matchbracketclass reads it
+fn lint_set(p: &[u8], start: usize, end: usize, res: &mut Vec<Warning>) {
+	let last = end - 1;
+	let mut j = start + 1;
+	if p[j] == b'^' {
+		j += 1;
+	}
+	while j < last {
+		if p[j] == b'%' {
+			if needless_escape(p[j + 1]) {
+				res.push(Warning{kind: WarningKind::NeedlessEscape, span: j..j + 2});
+			}
+			j += 2;
+		} else if p[j + 1] == b'-' && j + 2 < last {
+			j += 3;
+		} else {
+			if p[j + 1] == b'-' {
+				res.push(Warning{kind: WarningKind::TrailingDash, span: j..j + 2});
+			}
+			j += 1;
+		}
+	}
+}
+
+fn lint_checked(p: &[u8], template: Option<&[Subst]>) -> Vec<Warning> {
+	let items: Vec<_> = Items::new(p).spanned().collect();
+	let mut res = Vec::new();
+	// where each capture is, and whether it is used
+	let mut captures: Vec<(ops::Range<usize>, bool)> = Vec::new();
+	let mut open = Vec::new();
+	for (k, (span, item)) in items.iter().enumerate() {
+		match *item {
+			Item::Open => {
+				open.push(captures.len());
+				captures.push((span.clone(), false));
+			},
+			Item::Close => {
+				let i = open.pop().expect("checked pattern");
+				captures[i].0.end = span.end;
+			},
+			Item::Position => captures.push((span.clone(), false)),
+			Item::BackRef(l) => captures[l].1 = true,
+			Item::Frontier(_) => lint_set(p, span.start + 2, span.end, &mut res),
+			Item::Single(class, suffix) => match *class {
+				[b'[', ..] => lint_set(p, span.start, span.start + class.len(), &mut res),
+				[b'%', c] if needless_escape(c) => {
+					res.push(Warning{kind: WarningKind::NeedlessEscape, span: span.start..span.start + 2});
+				},
+				[b'.'] => {
+					let next = items[k + 1..].iter().map(|(_, item)| item).find(|&&item| item != Item::Close);
+					if suffix == Some(b'*') && next == Some(&Item::End) {
+						res.push(Warning{kind: WarningKind::RedundantEnd, span: span.start..p.len()});
+					}
+				},
+				[_] if suffix == Some(b'-') => {
+					res.push(Warning{kind: WarningKind::LazyDash, span: span.clone()});
+				},
+				_ => {}
+			},
+			_ => {}
+		}
+	}
+	if let Some(template) = template {
+		for s in template {
+			if let Subst::Capture(i) = *s {
+				if i > 0 && i <= captures.len() {
+					captures[i - 1].1 = true;
+				}
+			}
+		}
+		for (i, (span, used)) in captures.into_iter().enumerate() {
+			if ! used {
+				res.push(Warning{kind: WarningKind::UnusedCapture(i + 1), span});
+			}
+		}
+		res.sort_by_key(|w| w.span.start);
+	}
+	res
+}
+
+/// Check a pattern for suspicious constructs, in order of where they are
+///
+/// Captures are not checked, since they may be used by the caller;
+/// see `lint_with_template`.
+pub fn lint(patt: &[u8]) -> Result<Vec<Warning>, PatternError> {
+	str_check(patt)?;
+	Ok(lint_checked(patt, None))
+}
+
+/// Check a pattern used with a `gsub` replacement template
+///
+/// Besides what `lint` finds, this reports captures used neither
+/// by a back reference nor by the template.
+///
+/// ```
+/// use lua_patterns2::lint::{lint_with_template, WarningKind};
+/// let warnings = lint_with_template(b"(%w+)=(%w+)", "%2").unwrap();
+/// assert_eq!(warnings.len(), 1);
+/// assert_eq!(warnings[0].kind, WarningKind::UnusedCapture(1));
+/// assert_eq!(warnings[0].span, 0..5);
+/// assert_eq!(warnings[0].to_string(), "0..5: capture #1 is never referenced");
+/// ```
+pub fn lint_with_template(patt: &[u8], template: &str) -> Result<Vec<Warning>, PatternError> {
+	str_check(patt)?;
+	Ok(lint_checked(patt, Some(&generate_gsub_patterns(template))))
+}
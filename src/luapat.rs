@@ -4,6 +4,8 @@ use core::result;
 use core::ptr::null;
 
 use crate::errors::*;
+use crate::info::PatternInfo;
+use crate::MatchKind;
 
 pub const LUA_MAXCAPTURES: usize = 32;
 /* maximum recursion depth for 'match' */
@@ -53,13 +55,6 @@ impl CapLen {
 		}
 	}
 
-	fn size(&self) -> Result<usize> {
-		match *self {
-			CapLen::Len(size) => Ok(size),
-			_ => Err(PatternError::NoCaptureLength),
-		}
-	}
-
 }
 
 type CPtr = *const u8;
@@ -85,17 +80,28 @@ struct MatchState {
 	p_end: CPtr, /* end ('\0') of pattern */
 	level: usize, /* total number of captures (finished or unfinished) */
 	capture: [Capture; LUA_MAXCAPTURES],
+	hit_end: bool, /* did matching need to look past the end of the source? */
+	longest: bool, /* keep looking for a longer match after finding one? */
+	best: CPtr, /* end of the longest match so far */
+	best_level: usize,
+	best_capture: [Capture; LUA_MAXCAPTURES],
 }
 
 impl MatchState {
 	fn new(s: CPtr, se: CPtr, pe: CPtr) -> MatchState {
+		let no_capture = Capture{init: null(), len: CapLen::Len(0) };
 		MatchState {
 			matchdepth: MAXCCALLS,
 			src_init: s,
 			src_end: se,
 			p_end: pe,
 			level: 0,
-			capture: [Capture{init: null(), len: CapLen::Len(0) }; LUA_MAXCAPTURES],
+			capture: [no_capture; LUA_MAXCAPTURES],
+			hit_end: false,
+			longest: false,
+			best: null(),
+			best_level: 0,
+			best_capture: [no_capture; LUA_MAXCAPTURES],
 		}
 	}
 
@@ -162,7 +168,7 @@ fn match_class (ch: u8, class: u8) -> bool {
 		b'u' => ch.is_ascii_uppercase(),
 		b'w' => ch.is_ascii_alphanumeric(),
 		b'x' => ch.is_ascii_hexdigit(),
-		lc => return lc == ch,
+		_ => return class == ch, /* an escaped character matches itself */
 	};
 	if class.is_ascii_lowercase() { res } else {! res}
 }
@@ -201,10 +207,23 @@ fn matchbracketclass (c: u8, p: CPtr, ec: CPtr) -> bool {
 	return ! sig;
 }
 
+/* does a single class, as written in a pattern (e.g. "x", ".", "%a", "[^%s]"), match c? */
+#[cfg(feature = "std")]
+pub fn class_match(c: u8, class: &[u8]) -> bool {
+	let p = class.as_ptr();
+	match class[0] {
+		b'.' => true,
+		L_ESC => match_class(c, class[1]),
+		b'[' => matchbracketclass(c, p, add(p, class.len() - 1)),
+		pc => c == pc
+	}
+}
+
 impl MatchState {
 
-	fn singlematch (&self, s: CPtr, p: CPtr, ep: CPtr) -> bool {
+	fn singlematch (&mut self, s: CPtr, p: CPtr, ep: CPtr) -> bool {
 		if s >= self.src_end {
+			self.hit_end = true;
 			return false;
 		}
 		let c = at(s);
@@ -217,10 +236,14 @@ impl MatchState {
 		}
 	}
 
-	fn matchbalance (&self, s: CPtr, p: CPtr) -> Result<CPtr> {
+	fn matchbalance (&mut self, s: CPtr, p: CPtr) -> Result<CPtr> {
 		if p >= sub(self.p_end,1) {
 			return Err(PatternError::MalformedBalance);
 		}
+		if s >= self.src_end {
+			self.hit_end = true;
+			return Ok(null());
+		}
 		if at(s) != at(p) {
 			return Ok(null());
 		}
@@ -242,6 +265,7 @@ impl MatchState {
 			}
 			s = next(s);
 		}
+		self.hit_end = true;
 		Ok(null()) /* string ends out of balance */
 	}
 
@@ -303,10 +327,20 @@ impl MatchState {
 
 	fn match_capture(&mut self, s: CPtr, l: usize) -> Result<CPtr> {
 		let l = self.check_capture(l)?;
-		let len = self.capture[l].len.size()?;
-		if diff(self.src_end, s) >= len {
-			unsafe {s.copy_to_nonoverlapping(self.capture[l].init as *mut u8, len);}
-			return Ok(add(s,len));
+		let len = match self.capture[l].len {
+			CapLen::Len(len) => len,
+			_ => return Ok(null()) /* a position capture matches no text, as in Lua */
+		};
+		let avail = diff(self.src_end, s).min(len);
+		let (cap, here) = unsafe {(
+			core::slice::from_raw_parts(self.capture[l].init, avail),
+			core::slice::from_raw_parts(s, avail)
+		)};
+		if cap == here {
+			if avail == len {
+				return Ok(add(s,len));
+			}
+			self.hit_end = true; /* only the start of the capture is here */
 		}
 		Ok(null())
 	}
@@ -322,6 +356,16 @@ impl MatchState {
 
 		if p == self.p_end {  /* end of pattern? */
 			self.matchdepth += 1;
+			if self.longest {
+				if self.best.is_null() || s > self.best {
+					self.best = s;
+					self.best_level = self.level;
+					self.best_capture = self.capture;
+				}
+				if s < self.src_end { /* could still be longer; backtrack */
+					return Ok(null());
+				}
+			}
 			return Ok(s);
 		}
 		match at(p) {
@@ -358,9 +402,13 @@ impl MatchState {
 						}
 						let ep = self.classend(p)?; /* points to what is next */
 						let previous = if s == self.src_init {b'\0'} else {at(sub(s,1))};
+						let current = if s < self.src_end {at(s)} else {
+							self.hit_end = true;
+							b'\0'
+						};
 						let epl = sub(ep,1);
 						if ! matchbracketclass(previous,p,epl)
-						   && matchbracketclass(at(s),p,epl) {
+						   && matchbracketclass(current,p,epl) {
 							return self.patt_match(s, ep);
 						}
 						s = null(); /* match failed */
@@ -386,16 +434,17 @@ impl MatchState {
 		let mut s = s;
 		/* pattern class plus optional suffix */
 		let ep = self.classend(p)?; /* points to optional suffix */
+		/* the pattern isn't NUL-terminated as in C, so don't read past its end */
+		let epc = if ep < self.p_end {at(ep)} else {b'\0'};
 		/* does not match at least once? */
 		if ! self.singlematch(s, p, ep) {
-			let epc = at(ep);
 			if epc == b'*' || epc == b'?' || epc == b'-' { /* accept empty? */
 				return self.patt_match(s, next(ep));
 			} else { /* '+' or no suffix */
 				s = null(); /* fail */
 			}
 		} else { /* matched once */
-			match at(ep) { /* handle optional suffix */
+			match epc { /* handle optional suffix */
 				b'?' => {
 					let res = self.patt_match(next(s),next(ep))?;
 					if ! res.is_null() {
@@ -468,18 +517,20 @@ impl MatchState {
 			p = next(p);
 			match ch {
 				L_ESC => {
-					//p = next(p);
+					if p >= self.p_end {
+						return Err(PatternError::EndsWithEscape);
+					}
 					let c = at(p);
 					match c {
 						b'b' => {
-							p = next(p);
-							if p >= self.p_end {
+							if add(p,2) >= self.p_end { /* needs two delimiters */
 								return Err(PatternError::MalformedBalance);
 							}
+							p = add(p,3); /* delimiters are not magic */
 						},
 						b'f' => {
 							p = next(p);
-							if at(p) != b'[' {
+							if p >= self.p_end || at(p) != b'[' {
 								return Err(PatternError::MalformedFrontier);
 							}
 							p = sub(p,1); // so we see [...]
@@ -490,24 +541,26 @@ impl MatchState {
 							if l < 0 || l as usize >= self.level || self.capture[l as usize].is_unfinished() {
 								return Err(PatternError::InvalidCaptureIndex(Some(l)));
 							}
-							p = sub(p,1);
+							p = next(p);
 						},
-						_ => {}
+						_ => {
+							p = next(p); /* escaped character is not magic */
+						}
 					}
 				},
 				b'[' => {
-					while at(p) != b']' {
-						if p == self.p_end {
-							return Err(PatternError::UnfinishedCharClass);
-						}
-						if at(p) == L_ESC && p < self.p_end {
+					while p < self.p_end && at(p) != b']' {
+						if at(p) == L_ESC {
 							p = next(p);
 						}
 						p = next(p);
 					}
+					if p >= self.p_end {
+						return Err(PatternError::UnfinishedCharClass);
+					}
 				},
 				b'(' => {
-					if at(p) != b')' { // not a position capture
+					if p == self.p_end || at(p) != b')' { // not a position capture
 						level_stack[stack_idx] = self.level;
 						stack_idx += 1;
 						self.capture[self.level].len = CapLen::Unfinished;
@@ -515,7 +568,12 @@ impl MatchState {
 						if self.level >= LUA_MAXCAPTURES {
 							return Err(PatternError::TooManyCaptures);
 						}
-					} else {
+					} else { /* position captures are numbered too */
+						self.capture[self.level].len = CapLen::Position;
+						self.level += 1;
+						if self.level >= LUA_MAXCAPTURES {
+							return Err(PatternError::TooManyCaptures);
+						}
 						p = next(p);
 					}
 				},
@@ -536,46 +594,118 @@ impl MatchState {
 	}
 }
 
-pub fn str_match(s: &[u8], p: &[u8], mm: &mut [LuaMatch]) -> Result<usize> {
-	let mut lp = p.len();
-	let mut p = p.as_ptr();
-	let ls = s.len();
-	let s = s.as_ptr();
-	let mut s1 = s;
-	let anchor = at(p) == b'^';
-	if anchor {
-		p = next(p);
-		lp -= 1;  /* skip anchor character */
+impl MatchState {
+	/* forget captures and depth left over from the previous start position */
+	fn reprepstate(&mut self) {
+		self.level = 0;
+		self.matchdepth = MAXCCALLS;
+		self.best = null();
 	}
 
-	let mut ms = MatchState::new(s,add(s,ls),add(p,lp));
-	loop {
-		let res = ms.patt_match(s1, p)?;
-		if ! res.is_null() {
-			mm[0].start = diff(s1,s); /* start */
-			mm[0].end = diff(res,s); /* end */
-			return Ok(ms.push_captures(null(),null(),&mut mm[1..])? + 1);
+	/* try to match starting at s1, filling in captures on success */
+	fn match_at(&mut self, s1: CPtr, p: CPtr, mm: &mut [LuaMatch]) -> Result<usize> {
+		self.reprepstate();
+		let mut res = self.patt_match(s1, p)?;
+		if res.is_null() && ! self.best.is_null() { /* take the longest match seen */
+			res = self.best;
+			self.level = self.best_level;
+			self.capture = self.best_capture;
 		}
-		s1 = next(s1);
-		if ! (s1 < ms.src_end && ! anchor) {
-			break;
+		if res.is_null() {
+			return Ok(0);
+		}
+		mm[0].start = diff(s1,self.src_init); /* start */
+		mm[0].end = diff(res,self.src_init); /* end */
+		Ok(self.push_captures(null(),null(),&mut mm[1..])? + 1)
+	}
+}
+
+/* the first and last start positions that could possibly match */
+fn start_range(ls: usize, info: &PatternInfo) -> Option<(usize, usize)> {
+	if ls < info.min_len { /* too short for any match */
+		return None;
+	}
+	if info.anchored_start {
+		return Some((0, 0));
+	}
+	let mut first = 0;
+	if info.anchored_end {
+		/* a match ending at the end cannot start further back than its maximum length */
+		if let Some(max) = info.max_len {
+			first = ls.saturating_sub(max);
+		}
+	}
+	/* the last start leaves enough input; like Lua 5.2, never try at the very end */
+	let last = (ls - info.min_len).min(ls.saturating_sub(1));
+	if first > last {
+		return None;
+	}
+	Some((first, last))
+}
+
+fn match_state(s: &[u8], p: &[u8], info: &PatternInfo, kind: MatchKind) -> (MatchState, CPtr) {
+	let p = if info.anchored_start {&p[1..]} else {p}; /* skip anchor character */
+	let mut ms = MatchState::new(s.as_ptr(), add(s.as_ptr(), s.len()), add(p.as_ptr(), p.len()));
+	ms.longest = kind == MatchKind::LeftmostLongest;
+	(ms, p.as_ptr())
+}
+
+pub fn str_match(s: &[u8], p: &[u8], info: &PatternInfo, kind: MatchKind, mm: &mut [LuaMatch]) -> Result<usize> {
+	let (first, last) = match start_range(s.len(), info) {
+		Some(range) => range,
+		None => return Ok(0)
+	};
+	let (mut ms, p) = match_state(s, p, info, kind);
+	for i in first..=last {
+		let n = ms.match_at(add(ms.src_init,i), p, mm)?;
+		if n > 0 {
+			return Ok(n);
+		}
+	}
+	Ok(0)
+}
+
+/*
+** like str_match, but the source may be cut short: every start position is
+** tried, including the end, and also reports if matching needed more input
+*/
+pub fn str_match_partial(s: &[u8], p: &[u8], info: &PatternInfo, kind: MatchKind, mm: &mut [LuaMatch]) -> Result<(usize, bool)> {
+	let last = if info.anchored_start {0} else {s.len()};
+	let (mut ms, p) = match_state(s, p, info, kind);
+	for i in 0..=last {
+		let n = ms.match_at(add(ms.src_init,i), p, mm)?;
+		if n > 0 {
+			return Ok((n, ms.hit_end));
+		}
+	}
+	Ok((0, ms.hit_end))
+}
+
+/* like str_match, but finds the match which starts furthest to the right */
+pub fn str_rmatch(s: &[u8], p: &[u8], info: &PatternInfo, kind: MatchKind, mm: &mut [LuaMatch]) -> Result<usize> {
+	let (first, last) = match start_range(s.len(), info) {
+		Some(range) => range,
+		None => return Ok(0)
+	};
+	let (mut ms, p) = match_state(s, p, info, kind);
+	for i in (first..=last).rev() {
+		let n = ms.match_at(add(ms.src_init,i), p, mm)?;
+		if n > 0 {
+			return Ok(n);
 		}
 	}
 	Ok(0)
 }
 
 pub fn str_check(p: &[u8]) -> Result<()> {
+	let anchor = p.first() == Some(&b'^');
 	let mut lp = p.len();
 	let mut p = p.as_ptr();
-	let anchor = at(p) == b'^';
 	if anchor {
 		p = next(p);
 		lp -= 1;  /* skip anchor character */
 	}
 	let mut ms = MatchState::new(null(),null(),add(p,lp));
-	if at(sub(ms.p_end,1)) == b'%' {
-		return Err(PatternError::EndsWithEscape);
-	}
 	ms.str_match_check(p)?;
 	Ok(())
 }
@@ -0,0 +1,597 @@
+//! Convert between Lua patterns and regular expressions.
+//!
+//! `LuaPattern::to_regex` writes a pattern for `regex::bytes::Regex` from
+//! the `regex` crate, matching the same bytes as the pattern: it starts
+//! with `(?s-u)` so that `.` matches any byte and classes are ASCII, as in Lua.
+//!
+//! ```
+//! use lua_patterns2::LuaPattern;
+//! use lua_patterns2::errors::RegexError;
+//!
+//! let m = LuaPattern::new("^(%a+)=([^;]-);?$");
+//! assert_eq!(m.to_regex().unwrap(), r"(?s-u)^([[:alpha:]]+)=([^;]*?);?$");
+//! assert_eq!(LuaPattern::new("%b()").to_regex(), Err(RegexError::Balance));
+//! ```
+//!
+//! `LuaPatternBuilder::regex` goes the other way, for a subset of
+//! regular expressions.
+//!
+//! ```
+//! let patt = lua_patterns2::LuaPatternBuilder::new()
+//!     .regex(r"^\d{3}-\w+$").unwrap()
+//!     .build();
+//! assert_eq!(patt, b"^%d%d%d%-[%w_]+$");
+//! ```
+
+use core::fmt::Write;
+use std::format;
+use std::string::String;
+use std::vec::Vec;
+
+use crate::ast::{self, Class, Item, Quantifier, Set};
+use crate::errors::{FromRegexError, RegexError};
+use crate::{LuaPattern, LuaPatternBuilder};
+
+/// The largest count allowed in a repeat like `{n,m}`, which
+/// `LuaPatternBuilder::regex` writes out in full
+pub const MAX_REGEX_REPEAT: usize = 1000;
+
+/// How to write a regular expression
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RegexOptions {
+	/// Write `%f` with lookaround, which the `regex` crate lacks
+	/// but engines like PCRE have
+	pub lookaround: bool,
+}
+
+// the class as it goes inside brackets; `\s` would also match '\x0B',
+// which `%s` doesn't, so that is a nested set
+fn class_regex(class: Class, negated: bool) -> &'static str {
+	match (class, negated) {
+		(Class::Digit, false) => r"\d",
+		(Class::Digit, true) => r"\D",
+		(Class::Space, false) => r"[\t\n\x0C\r ]",
+		(Class::Space, true) => r"[^\t\n\x0C\r ]",
+		(Class::Letter, false) => "[:alpha:]",
+		(Class::Letter, true) => "[:^alpha:]",
+		(Class::Control, false) => "[:cntrl:]",
+		(Class::Control, true) => "[:^cntrl:]",
+		(Class::Graphic, false) => "[:graph:]",
+		(Class::Graphic, true) => "[:^graph:]",
+		(Class::Lower, false) => "[:lower:]",
+		(Class::Lower, true) => "[:^lower:]",
+		(Class::Punct, false) => "[:punct:]",
+		(Class::Punct, true) => "[:^punct:]",
+		(Class::Upper, false) => "[:upper:]",
+		(Class::Upper, true) => "[:^upper:]",
+		(Class::AlphaNum, false) => "[:alnum:]",
+		(Class::AlphaNum, true) => "[:^alnum:]",
+		(Class::HexDigit, false) => "[:xdigit:]",
+		(Class::HexDigit, true) => "[:^xdigit:]",
+	}
+}
+
+fn push_byte(c: u8, in_set: bool, out: &mut String) {
+	let meta: &[u8] = if in_set {b"\\[]^-&~"} else {b"\\.+*?()|[]{}^$"};
+	if meta.contains(&c) {
+		out.push('\\');
+		out.push(c as char);
+	} else if c.is_ascii_graphic() || c == b' ' {
+		out.push(c as char);
+	} else {
+		let _ = write!(out, r"\x{:02X}", c);
+	}
+}
+
+fn push_set(set: &Set, negated: bool, out: &mut String) {
+	let ranges: Vec<_> = set.ranges.iter().filter(|&&(lo, hi)| lo <= hi).collect();
+	if ranges.is_empty() && set.classes.is_empty() {
+		// "[]" matches nothing and "[^]" anything
+		out.push_str(if negated {r"[\x00-\xFF]"} else {r"[^\x00-\xFF]"});
+		return;
+	}
+	out.push('[');
+	if negated {
+		out.push('^');
+	}
+	for &(class, negated) in &set.classes {
+		out.push_str(class_regex(class, negated));
+	}
+	for &&(lo, hi) in &ranges {
+		push_byte(lo, true, out);
+		if lo != hi {
+			out.push('-');
+			push_byte(hi, true, out);
+		}
+	}
+	out.push(']');
+}
+
+fn push_single(item: &Item, out: &mut String) {
+	match *item {
+		Item::Literal(c) => push_byte(c, false, out),
+		Item::Any => out.push('.'),
+		Item::Class{class, negated} => match class {
+			Class::Digit | Class::Space => out.push_str(class_regex(class, negated)),
+			_ => {
+				out.push('[');
+				out.push_str(class_regex(class, negated));
+				out.push(']');
+			}
+		},
+		Item::Set(ref set) => push_set(set, set.negated, out),
+		_ => {}
+	}
+}
+
+// does the set hold the '\0' which Lua sees beyond either end?
+fn has_nul(set: &Set) -> bool {
+	let found = set.ranges.iter().any(|&(lo, hi)| lo == 0 && hi >= lo)
+		|| set.classes.iter().any(|&(class, negated)| (class == Class::Control) != negated);
+	found != set.negated
+}
+
+fn push_items(items: &[Item], options: RegexOptions, out: &mut String) -> Result<(), RegexError> {
+	for item in items {
+		match *item {
+			Item::Start => out.push('^'),
+			Item::End => out.push('$'),
+			Item::Quantified{ref item, quantifier} => {
+				push_single(item, out);
+				out.push_str(match quantifier {
+					Quantifier::ZeroOrMore => "*",
+					Quantifier::OneOrMore => "+",
+					Quantifier::Lazy => "*?",
+					Quantifier::Optional => "?",
+				});
+			},
+			Item::Capture{ref items, ..} => {
+				out.push('(');
+				push_items(items, options, out)?;
+				out.push(')');
+			},
+			Item::PositionCapture{..} => out.push_str("()"),
+			Item::Balance{..} => return Err(RegexError::Balance),
+			Item::BackRef(_) => return Err(RegexError::BackRef),
+			Item::Frontier(ref set) => {
+				if ! options.lookaround {
+					return Err(RegexError::Frontier);
+				}
+				if has_nul(set) {
+					// the previous byte must exist, but the next need not
+					out.push_str("(?<=");
+					push_set(set, ! set.negated, out);
+					out.push_str(")(?!");
+					push_set(set, ! set.negated, out);
+				} else {
+					out.push_str("(?<!");
+					push_set(set, set.negated, out);
+					out.push_str(")(?=");
+					push_set(set, set.negated, out);
+				}
+				out.push(')');
+			},
+			ref single => push_single(single, out)
+		}
+	}
+	Ok(())
+}
+
+impl <'a> LuaPattern<'a> {
+	/// The pattern as a regular expression for the `regex` crate
+	///
+	/// Fails for `%b`, back references and `%f`.
+	///
+	/// ```
+	/// let m = lua_patterns2::LuaPattern::new("%s*(%d+)%.?()");
+	/// assert_eq!(m.to_regex().unwrap(), r"(?s-u)[\t\n\x0C\r ]*(\d+)\.?()");
+	/// ```
+	pub fn to_regex(&self) -> Result<String, RegexError> {
+		self.to_regex_with(RegexOptions::default())
+	}
+
+	/// The pattern as a regular expression, with options
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, RegexOptions};
+	/// let m = LuaPattern::new("%f[%w]%w+");
+	/// let options = RegexOptions{lookaround: true};
+	/// assert_eq!(m.to_regex_with(options).unwrap(),
+	///     "(?s-u)(?<![[:alnum:]])(?=[[:alnum:]])[[:alnum:]]+");
+	/// ```
+	pub fn to_regex_with(&self, options: RegexOptions) -> Result<String, RegexError> {
+		let mut out = String::from("(?s-u)");
+		push_items(&self.parse(), options, &mut out)?;
+		Ok(out)
+	}
+}
+
+// the part of a regular expression a quantifier follows
+enum Atom {
+	// a character, to be escaped
+	Literal(char),
+	// pattern text for a single character item
+	Class(String),
+}
+
+enum Escape {
+	Class(Class, bool),
+	// \w or \W
+	Word(bool),
+	// \s or \S, which unlike %s take in '\x0B'
+	Space(bool),
+	Char(char),
+}
+
+fn set_text(set: Set) -> String {
+	String::from_utf8(ast::print(&[Item::Set(set)])).expect("sets are ASCII")
+}
+
+fn word_set(negated: bool) -> Set {
+	Set{negated, ranges: std::vec![(b'_', b'_')], classes: std::vec![(Class::AlphaNum, false)]}
+}
+
+fn space_set(negated: bool) -> Set {
+	Set{negated, ranges: std::vec![(0x0B, 0x0B)], classes: std::vec![(Class::Space, false)]}
+}
+
+fn ascii(c: char, at: usize) -> Result<u8, FromRegexError> {
+	if c.is_ascii() {Ok(c as u8)} else {Err(FromRegexError::Unsupported(at))}
+}
+
+struct RegexParser<'r> {
+	re: &'r str,
+	i: usize,
+}
+
+impl <'r> RegexParser<'r> {
+	fn peek(&self) -> Option<u8> {
+		self.re.as_bytes().get(self.i).copied()
+	}
+
+	fn eat(&mut self, c: u8) -> bool {
+		let found = self.peek() == Some(c);
+		if found {
+			self.i += 1;
+		}
+		found
+	}
+
+	fn next_char(&mut self) -> Option<char> {
+		let c = self.re[self.i..].chars().next()?;
+		self.i += c.len_utf8();
+		Some(c)
+	}
+
+	fn number(&mut self) -> Option<usize> {
+		let digits = self.re[self.i..].bytes().take_while(u8::is_ascii_digit).count();
+		let n = self.re[self.i..self.i + digits].parse().ok()?;
+		self.i += digits;
+		Some(n)
+	}
+
+	// after a backslash at `at`
+	fn escape(&mut self, at: usize) -> Result<Escape, FromRegexError> {
+		let c = self.next_char().ok_or(FromRegexError::Syntax(at))?;
+		Ok(match c {
+			'd' | 'D' => Escape::Class(Class::Digit, c == 'D'),
+			's' | 'S' => Escape::Space(c == 'S'),
+			'w' | 'W' => Escape::Word(c == 'W'),
+			'n' => Escape::Char('\n'),
+			't' => Escape::Char('\t'),
+			'r' => Escape::Char('\r'),
+			'f' => Escape::Char('\x0C'),
+			'v' => Escape::Char('\x0B'),
+			'x' => {
+				let braced = self.eat(b'{');
+				let len = if braced {
+					self.re[self.i..].find('}').ok_or(FromRegexError::Syntax(at))?
+				} else {
+					2
+				};
+				let hex = self.re.get(self.i..self.i + len).ok_or(FromRegexError::Syntax(at))?;
+				let c = u32::from_str_radix(hex, 16).ok()
+					.and_then(char::from_u32)
+					.ok_or(FromRegexError::Syntax(at))?;
+				self.i += len + braced as usize;
+				Escape::Char(c)
+			},
+			c if c.is_ascii_punctuation() || c == ' ' => Escape::Char(c),
+			_ => return Err(FromRegexError::Unsupported(at))
+		})
+	}
+
+	// after "[:" at `at`
+	fn posix_class(&mut self, at: usize, set: &mut Set) -> Result<(), FromRegexError> {
+		let len = self.re[self.i..].find(":]").ok_or(FromRegexError::Syntax(at))?;
+		let name = &self.re[self.i..self.i + len];
+		self.i += len + 2;
+		let (negated, name) = match name.strip_prefix('^') {
+			Some(name) => (true, name),
+			None => (false, name)
+		};
+		let class = match name {
+			"alpha" => Class::Letter,
+			"cntrl" => Class::Control,
+			"digit" => Class::Digit,
+			"graph" => Class::Graphic,
+			"lower" => Class::Lower,
+			"punct" => Class::Punct,
+			"space" if ! negated => {
+				set.ranges.push((0x0B, 0x0B));
+				Class::Space
+			},
+			"upper" => Class::Upper,
+			"alnum" => Class::AlphaNum,
+			"xdigit" => Class::HexDigit,
+			"word" if ! negated => {
+				set.ranges.push((b'_', b'_'));
+				Class::AlphaNum
+			},
+			_ => return Err(FromRegexError::Unsupported(at))
+		};
+		set.classes.push((class, negated));
+		Ok(())
+	}
+
+	// after '[' at `start`
+	fn set(&mut self, start: usize) -> Result<Set, FromRegexError> {
+		let mut set = Set{negated: self.eat(b'^'), ..Set::default()};
+		let first = self.i;
+		loop {
+			let at = self.i;
+			let c = self.next_char().ok_or(FromRegexError::Syntax(start))?;
+			let lo = match c {
+				// a ']' straight away is literal
+				']' if at != first => return Ok(set),
+				'[' if self.eat(b':') => {
+					self.posix_class(at, &mut set)?;
+					continue;
+				},
+				// nested classes and set operations
+				'[' => return Err(FromRegexError::Unsupported(at)),
+				'&' | '-' | '~' if self.peek() == Some(c as u8) => return Err(FromRegexError::Unsupported(at)),
+				'\\' => match self.escape(at)? {
+					Escape::Class(class, negated) => {
+						set.classes.push((class, negated));
+						continue;
+					},
+					Escape::Word(false) => {
+						set.ranges.push((b'_', b'_'));
+						set.classes.push((Class::AlphaNum, false));
+						continue;
+					},
+					Escape::Space(false) => {
+						set.ranges.push((0x0B, 0x0B));
+						set.classes.push((Class::Space, false));
+						continue;
+					},
+					Escape::Word(true) | Escape::Space(true) => return Err(FromRegexError::Unsupported(at)),
+					Escape::Char(c) => c
+				},
+				c => c
+			};
+			let lo = ascii(lo, at)?;
+			// a range, unless the '-' is last
+			let after_dash = self.re.as_bytes().get(self.i + 1);
+			if self.peek() == Some(b'-') && after_dash.is_some() && after_dash != Some(&b']') {
+				self.i += 1;
+				let at = self.i;
+				let hi = match self.next_char() {
+					Some('\\') => match self.escape(at)? {
+						Escape::Char(c) => c,
+						_ => return Err(FromRegexError::Syntax(at))
+					},
+					Some('[') => return Err(FromRegexError::Unsupported(at)),
+					Some(c) => c,
+					None => return Err(FromRegexError::Syntax(start))
+				};
+				let hi = ascii(hi, at)?;
+				if hi < lo {
+					return Err(FromRegexError::Syntax(at));
+				}
+				set.ranges.push((lo, hi));
+			} else {
+				set.ranges.push((lo, lo));
+			}
+		}
+	}
+
+	fn atom(&mut self) -> Result<Atom, FromRegexError> {
+		let at = self.i;
+		Ok(match self.next_char() {
+			// '.' does not match a newline
+			Some('.') => Atom::Class(set_text(Set{negated: true, ranges: std::vec![(b'\n', b'\n')], classes: Vec::new()})),
+			Some('[') => Atom::Class(set_text(self.set(at)?)),
+			Some('\\') => match self.escape(at)? {
+				Escape::Class(class, negated) => Atom::Class(format!("%{}", class.to_byte(negated) as char)),
+				Escape::Word(negated) => Atom::Class(set_text(word_set(negated))),
+				Escape::Space(negated) => Atom::Class(set_text(space_set(negated))),
+				Escape::Char(c) => Atom::Literal(c)
+			},
+			Some(c) => Atom::Literal(c),
+			None => return Err(FromRegexError::Syntax(at))
+		})
+	}
+
+	// `{n}`, `{n,}` or `{n,m}` after the '{' at `at`
+	fn counts(&mut self, at: usize) -> Result<(usize, Option<usize>), FromRegexError> {
+		let min = self.number().ok_or(FromRegexError::Syntax(at))?;
+		let max = if self.eat(b',') {
+			self.number()
+		} else {
+			Some(min)
+		};
+		let reversed = match max {
+			Some(max) => max < min,
+			None => false
+		};
+		if ! self.eat(b'}') || reversed {
+			return Err(FromRegexError::Syntax(at));
+		}
+		if max.unwrap_or(min) > MAX_REGEX_REPEAT {
+			return Err(FromRegexError::TooManyRepeats(at));
+		}
+		Ok((min, max))
+	}
+
+	fn quantified(&mut self, atom: Atom, b: &mut LuaPatternBuilder) -> Result<(), FromRegexError> {
+		let emit = |b: &mut LuaPatternBuilder, suffix: &str| {
+			match atom {
+				Atom::Literal(c) => b.bytes(c.encode_utf8(&mut [0; 4]).as_bytes()),
+				Atom::Class(ref text) => b.text(text)
+			}.text(suffix);
+		};
+		let at = self.i;
+		let (min, max) = match self.peek() {
+			Some(b'*') => (0, None),
+			Some(b'+') => (1, None),
+			Some(b'?') => (0, Some(1)),
+			Some(b'{') => {
+				self.i += 1;
+				self.counts(at)?
+			},
+			_ => {
+				emit(b, "");
+				return Ok(());
+			}
+		};
+		if self.i == at {
+			self.i += 1;
+		}
+		let lazy = self.eat(b'?');
+		if matches!(self.peek(), Some(b'*' | b'+' | b'?' | b'{')) {
+			return Err(FromRegexError::Syntax(self.i));
+		}
+		if let Atom::Literal(c) = atom {
+			if c.len_utf8() > 1 {
+				return Err(FromRegexError::GroupQuantifier(at));
+			}
+		}
+		match (max, lazy) {
+			(None, false) if min > 0 => {
+				for _ in 1..min {
+					emit(b, "");
+				}
+				emit(b, "+");
+			},
+			(None, _) => {
+				for _ in 0..min {
+					emit(b, "");
+				}
+				emit(b, if lazy {"-"} else {"*"});
+			},
+			(Some(max), true) if max > min => return Err(FromRegexError::Unsupported(at)),
+			(Some(max), _) => {
+				for _ in 0..min {
+					emit(b, "");
+				}
+				for _ in min..max {
+					emit(b, "?");
+				}
+			}
+		}
+		Ok(())
+	}
+
+	// after '(' at `at`
+	fn group(&mut self, at: usize, depth: usize, b: &mut LuaPatternBuilder) -> Result<(), FromRegexError> {
+		let capture = if self.eat(b'?') {
+			let rest = &self.re[self.i..];
+			if self.eat(b':') {
+				false
+			} else if rest.starts_with("P<") || (rest.starts_with('<') && ! rest.starts_with("<=") && ! rest.starts_with("<!")) {
+				// a named group
+				self.i += rest.find('>').ok_or(FromRegexError::Syntax(at))? + 1;
+				true
+			} else {
+				return Err(FromRegexError::Unsupported(at));
+			}
+		} else {
+			true
+		};
+		if capture {
+			// "()" would capture the position
+			if self.peek() == Some(b')') {
+				return Err(FromRegexError::Unsupported(at));
+			}
+			b.text("(");
+		}
+		self.seq(depth + 1, b)?;
+		if ! self.eat(b')') {
+			return Err(FromRegexError::Syntax(at));
+		}
+		if capture {
+			b.text(")");
+		}
+		if matches!(self.peek(), Some(b'*' | b'+' | b'?' | b'{')) {
+			return Err(FromRegexError::GroupQuantifier(self.i));
+		}
+		Ok(())
+	}
+
+	fn seq(&mut self, depth: usize, b: &mut LuaPatternBuilder) -> Result<(), FromRegexError> {
+		while let Some(c) = self.peek() {
+			let at = self.i;
+			match c {
+				b'|' => return Err(FromRegexError::Alternation(at)),
+				b')' if depth > 0 => return Ok(()),
+				b')' | b'*' | b'+' | b'?' | b'{' => return Err(FromRegexError::Syntax(at)),
+				// anchors only at either end
+				b'^' | b'$' => {
+					let end = c == b'$' && depth == 0 && at + 1 == self.re.len();
+					if at != 0 && ! end {
+						return Err(FromRegexError::Unsupported(at));
+					}
+					self.i += 1;
+					b.text(if c == b'^' {"^"} else {"$"});
+				},
+				b'(' => {
+					self.i += 1;
+					self.group(at, depth, b)?;
+				},
+				_ => {
+					let atom = self.atom()?;
+					self.quantified(atom, b)?;
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+impl LuaPatternBuilder {
+	/// Add a regular expression, written as a Lua pattern
+	///
+	/// The supported subset is: literal characters and escapes like `\.`,
+	/// `\n` and `\x41`; `.`, which does not match a newline; `\d`, `\w`,
+	/// `\s` and their negations; bracket sets with ranges, escapes
+	/// and ASCII classes like `[:alpha:]`; `*`, `+` and `?` after a single
+	/// character, along with `*?` and `+?`; repeats like `{3}`, `{2,}`
+	/// and `{1,4}`, counting up to [MAX_REGEX_REPEAT]; `^` and `$` at either
+	/// end; and groups, capturing or not, which are not repeated. Classes
+	/// are ASCII, as in Lua. `\s` also matches `\x0B`, unlike `%s`, so
+	/// `\S`, `\W`, `[:^space:]` and `[:^word:]` can't go in a set.
+	///
+	/// Nothing is added if there is an error.
+	///
+	/// ```
+	/// use lua_patterns2::LuaPatternBuilder;
+	/// use lua_patterns2::errors::FromRegexError;
+	///
+	/// let patt = LuaPatternBuilder::new()
+	///     .regex(r"(?P<key>[a-z_]+)\s*=\s*(.+?);").unwrap()
+	///     .build();
+	/// assert_eq!(patt, b"([a-z_]+)[%s\x0B]*=[%s\x0B]*([^\n][^\n]-);");
+	///
+	/// let mut b = LuaPatternBuilder::new();
+	/// assert_eq!(b.regex("cat|dog").unwrap_err(), FromRegexError::Alternation(3));
+	/// assert_eq!(b.regex("(ab)+").unwrap_err(), FromRegexError::GroupQuantifier(4));
+	/// ```
+	pub fn regex(&mut self, re: &str) -> Result<&mut Self, FromRegexError> {
+		let mut b = LuaPatternBuilder::new();
+		RegexParser{re, i: 0}.seq(0, &mut b)?;
+		let patt = b.build();
+		Ok(self.text(core::str::from_utf8(&patt).expect("escaped from a str")))
+	}
+}
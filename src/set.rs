@@ -0,0 +1,338 @@
+//! Match many patterns against the same input.
+//!
+//! Each pattern is looked at once, up front, to find the bytes a match
+//! can start with and a literal that every match must contain. Matching
+//! then only runs the patterns which pass these cheap checks.
+//!
+//! ```
+//! use lua_patterns2::{LuaPattern, LuaPatternSet};
+//!
+//! let mut set: LuaPatternSet = [
+//!     "^ERROR: (.*)",
+//!     "^WARN: (.*)",
+//!     "timeout after (%d+)ms",
+//! ].iter().map(|p| LuaPattern::new(p)).collect();
+//!
+//! let line = "ERROR: timeout after 300ms";
+//! assert_eq!(set.matches(line), &[0, 2]);
+//! let first = set.leftmost(line).unwrap();
+//! assert_eq!(first, 0);
+//! assert_eq!(set.get(2).match_captures(line).get(1), "300");
+//! ```
+
+use std::vec::Vec;
+use std::iter::FromIterator;
+
+use crate::LuaPattern;
+use crate::info::{Item, Items};
+use crate::luapat::class_match;
+
+// set of byte values
+#[derive(Debug, Clone, Copy, Default)]
+struct ByteSet([u64; 4]);
+
+impl ByteSet {
+	fn insert(&mut self, b: u8) {
+		self.0[(b >> 6) as usize] |= 1 << (b & 63);
+	}
+
+	fn contains(&self, b: u8) -> bool {
+		self.0[(b >> 6) as usize] & (1 << (b & 63)) != 0
+	}
+
+	fn of(bytes: &[u8]) -> ByteSet {
+		let mut set = ByteSet::default();
+		for &b in bytes {
+			set.insert(b);
+		}
+		set
+	}
+
+	fn add_class(&mut self, class: &[u8]) {
+		for b in 0..=255 {
+			if class_match(b, class) {
+				self.insert(b);
+			}
+		}
+	}
+}
+
+// bytes which can start a match; None if a match can be empty,
+// or start with whatever a back reference holds
+fn first_bytes(p: &[u8]) -> Option<ByteSet> {
+	let mut set = ByteSet::default();
+	for item in Items::new(p) {
+		match item {
+			Item::Open | Item::Close | Item::Position | Item::Frontier(_) => {},
+			Item::Balance(b, _) => {
+				set.insert(b);
+				return Some(set);
+			},
+			Item::Single(class, suffix) => {
+				set.add_class(class);
+				if suffix.is_none() || suffix == Some(b'+') {
+					return Some(set);
+				}
+			},
+			Item::BackRef(_) | Item::End => return None
+		}
+	}
+	None
+}
+
+// a single class which only matches one byte
+fn literal(class: &[u8]) -> Option<u8> {
+	match class {
+		[b'.'] => None,
+		[c] => Some(*c),
+		[b'%', c] if ! c.is_ascii_alphanumeric() => Some(*c),
+		_ => None
+	}
+}
+
+// the longest run of bytes that every match must contain
+fn required_literal(p: &[u8]) -> Vec<u8> {
+	let mut best = Vec::new();
+	let mut run = Vec::new();
+	for item in Items::new(p) {
+		let (c, more) = match item {
+			// these don't consume anything
+			Item::Open | Item::Close | Item::Position => continue,
+			Item::Single(class, None) => match literal(class) {
+				Some(c) => (Some(c), true),
+				None => (None, false)
+			},
+			Item::Single(class, Some(b'+')) => (literal(class), false),
+			_ => (None, false)
+		};
+		if let Some(c) = c {
+			run.push(c);
+		}
+		if ! more {
+			if run.len() > best.len() {
+				best = run.clone();
+			}
+			run.clear();
+		}
+	}
+	if run.len() > best.len() {
+		best = run;
+	}
+	best
+}
+
+/// A set of Lua patterns matched together against one input
+#[derive(Debug, Clone)]
+pub struct LuaPatternSet<'a> {
+	patterns: Vec<LuaPattern<'a>>,
+	literals: Vec<Vec<u8>>,
+	// anchored patterns, by the first byte of the input they can match
+	anchored: Vec<Vec<usize>>,
+	// unanchored patterns, by each byte they can start with
+	floating: Vec<Vec<usize>>,
+	// patterns which can't be filtered by their first byte
+	always: Vec<usize>,
+	// patterns with a required literal, by its first byte
+	by_literal: Vec<Vec<usize>>,
+	candidates: Vec<bool>,
+	// which required literals have been found
+	seen: Vec<bool>,
+}
+
+impl <'a> LuaPatternSet<'a> {
+	/// Create a set from patterns; their order gives their indices
+	pub fn new<I>(patterns: I) -> LuaPatternSet<'a>
+	where I: IntoIterator<Item = LuaPattern<'a>> {
+		let patterns: Vec<_> = patterns.into_iter().collect();
+		let mut set = LuaPatternSet{
+			literals: Vec::with_capacity(patterns.len()),
+			anchored: (0..256).map(|_| Vec::new()).collect(),
+			floating: (0..256).map(|_| Vec::new()).collect(),
+			always: Vec::new(),
+			by_literal: (0..256).map(|_| Vec::new()).collect(),
+			candidates: Vec::with_capacity(patterns.len()),
+			seen: Vec::with_capacity(patterns.len()),
+			patterns: Vec::new(),
+		};
+		for (i, m) in patterns.iter().enumerate() {
+			let literal = required_literal(m.patt);
+			if let Some(&b) = literal.first() {
+				set.by_literal[b as usize].push(i);
+			}
+			set.literals.push(literal);
+			match first_bytes(m.patt) {
+				Some(first) => {
+					let table = if m.info().anchored_start {&mut set.anchored} else {&mut set.floating};
+					for b in 0..=255 {
+						if first.contains(b) {
+							table[b as usize].push(i);
+						}
+					}
+				},
+				None => set.always.push(i)
+			}
+		}
+		set.patterns = patterns;
+		set
+	}
+
+	/// Number of patterns in the set
+	pub fn len(&self) -> usize {
+		self.patterns.len()
+	}
+
+	/// Is the set empty?
+	pub fn is_empty(&self) -> bool {
+		self.patterns.is_empty()
+	}
+
+	/// The ith pattern, holding the captures of its last match
+	pub fn get(&self, i: usize) -> &LuaPattern<'a> {
+		&self.patterns[i]
+	}
+
+	// mark the patterns which pass the first byte and literal checks
+	fn find_candidates(&mut self, s: &[u8]) {
+		self.candidates.clear();
+		self.candidates.resize(self.patterns.len(), false);
+		for &i in &self.always {
+			self.candidates[i] = true;
+		}
+		if let Some(&b) = s.first() {
+			for &i in &self.anchored[b as usize] {
+				self.candidates[i] = true;
+			}
+		}
+		let present = ByteSet::of(s);
+		for b in 0..=255 {
+			if present.contains(b) {
+				for &i in &self.floating[b as usize] {
+					self.candidates[i] = true;
+				}
+			}
+		}
+		self.find_literals(s);
+	}
+
+	// drop the candidates whose literal is not in the input,
+	// looking for all of the literals in one pass
+	fn find_literals(&mut self, s: &[u8]) {
+		self.seen.clear();
+		let mut pending = 0;
+		for (i, literal) in self.literals.iter().enumerate() {
+			let wanted = self.candidates[i] && ! literal.is_empty();
+			if wanted {
+				pending += 1;
+			}
+			self.seen.push(! wanted);
+		}
+		let mut j = 0;
+		while pending > 0 && j < s.len() {
+			for &i in &self.by_literal[s[j] as usize] {
+				if ! self.seen[i] && s[j..].starts_with(&self.literals[i]) {
+					self.seen[i] = true;
+					pending -= 1;
+				}
+			}
+			j += 1;
+		}
+		for (candidate, &seen) in self.candidates.iter_mut().zip(&self.seen) {
+			*candidate &= seen;
+		}
+	}
+
+	// run the remaining checks and then the match itself
+	fn try_match(&mut self, i: usize, s: &[u8]) -> bool {
+		self.candidates[i]
+			&& s.len() >= self.patterns[i].info().min_len
+			&& self.patterns[i].matches_bytes(s)
+	}
+
+	/// Collect the indices of all patterns matching a slice of bytes
+	///
+	/// Each matching pattern keeps its captures, see `get`.
+	pub fn matches_bytes_into(&mut self, s: &[u8], res: &mut Vec<usize>) -> bool {
+		res.clear();
+		self.find_candidates(s);
+		for i in 0..self.patterns.len() {
+			if self.try_match(i, s) {
+				res.push(i);
+			}
+		}
+		! res.is_empty()
+	}
+
+	/// The indices of all patterns matching a slice of bytes
+	pub fn matches_bytes(&mut self, s: &[u8]) -> Vec<usize> {
+		let mut res = Vec::new();
+		self.matches_bytes_into(s, &mut res);
+		res
+	}
+
+	/// The indices of all patterns matching a string
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, LuaPatternSet};
+	/// let mut set = LuaPatternSet::new(vec![
+	///     LuaPattern::new("%d+"), LuaPattern::new("^%a"), LuaPattern::new("x$")
+	/// ]);
+	/// assert_eq!(set.matches("abc 12"), &[0, 1]);
+	/// assert_eq!(set.matches("12x"), &[0, 2]);
+	/// assert!(set.matches("...").is_empty());
+	/// ```
+	pub fn matches(&mut self, text: &str) -> Vec<usize> {
+		self.matches_bytes(text.as_bytes())
+	}
+
+	/// Does any pattern match a string?
+	pub fn is_match(&mut self, text: &str) -> bool {
+		let s = text.as_bytes();
+		self.find_candidates(s);
+		(0..self.patterns.len()).any(|i| self.try_match(i, s))
+	}
+
+	/// The index of the pattern whose match starts first in a slice of bytes
+	///
+	/// Ties go to the pattern which comes first in the set.
+	pub fn leftmost_bytes(&mut self, s: &[u8]) -> Option<usize> {
+		self.find_candidates(s);
+		let mut best: Option<usize> = None;
+		for i in 0..self.patterns.len() {
+			if self.try_match(i, s) {
+				let start = self.patterns[i].range().start;
+				let better = match best {
+					Some(b) => start < self.patterns[b].range().start,
+					None => true
+				};
+				if better {
+					best = Some(i);
+				}
+			}
+		}
+		best
+	}
+
+	/// The index of the pattern whose match starts first in a string
+	///
+	/// Its captures are available through `get`.
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, LuaPatternSet};
+	/// let mut set = LuaPatternSet::new(vec![
+	///     LuaPattern::new("(%d+)ms"), LuaPattern::new("user=(%w+)")
+	/// ]);
+	/// let line = "user=bob took 12ms";
+	/// let i = set.leftmost(line).unwrap();
+	/// assert_eq!(i, 1);
+	/// assert_eq!(set.get(i).match_captures(line).get(1), "bob");
+	/// ```
+	pub fn leftmost(&mut self, text: &str) -> Option<usize> {
+		self.leftmost_bytes(text.as_bytes())
+	}
+}
+
+impl <'a> FromIterator<LuaPattern<'a>> for LuaPatternSet<'a> {
+	fn from_iter<I: IntoIterator<Item = LuaPattern<'a>>>(iter: I) -> Self {
+		LuaPatternSet::new(iter)
+	}
+}
@@ -0,0 +1,198 @@
+//! Match a pattern against a [BufRead] without reading all of it into memory.
+//!
+//! Only a bounded window of the input is kept: a match is assumed to be no
+//! longer than a maximum length, so once the window holds that much past a
+//! possible start, the match (or lack of one) cannot change by reading more.
+//!
+//! ```
+//! use lua_patterns2::{LuaPattern, StreamMatcher};
+//!
+//! let data: &[u8] = b"\x00\x01hello\xFF\xFEworld\x00";
+//! let m = LuaPattern::new("%a%a%a%a+");
+//! let words: Vec<_> = StreamMatcher::new(m, data)
+//!     .map(|res| res.unwrap())
+//!     .map(|w| (w.range().start, w.bytes().to_vec()))
+//!     .collect();
+//! assert_eq!(words, &[(2, b"hello".to_vec()), (9, b"world".to_vec())]);
+//! ```
+
+use std::io::{self, BufRead};
+use std::ops;
+use std::vec::Vec;
+
+use crate::LuaPattern;
+use crate::info::{Item, Items};
+
+/// Default for [StreamMatcher::max_match_len]
+pub const DEFAULT_MAX_MATCH_LEN: usize = 4096;
+
+/// An owned match from a [StreamMatcher], positioned in the whole stream
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamMatch {
+	offset: u64,
+	bytes: Vec<u8>,
+	captures: Vec<ops::Range<usize>>
+}
+
+impl StreamMatch {
+	/// The full match as offsets into the stream
+	pub fn range(&self) -> ops::Range<u64> {
+		self.capture(0)
+	}
+
+	/// The nth capture as offsets into the stream
+	pub fn capture(&self, i: usize) -> ops::Range<u64> {
+		let r = &self.captures[i];
+		ops::Range{
+			start: self.offset + r.start as u64,
+			end: self.offset + r.end as u64
+		}
+	}
+
+	/// The bytes of the full match
+	pub fn bytes(&self) -> &[u8] {
+		&self.bytes
+	}
+
+	/// get the capture as a byte slice
+	pub fn get(&self, i: usize) -> &[u8] {
+		&self.bytes[self.captures[i].clone()]
+	}
+
+	/// number of matches
+	pub fn num_matches(&self) -> usize {
+		self.captures.len()
+	}
+}
+
+/// Iterator over all matches of a pattern in a [BufRead]
+///
+/// This gives the same matches as `gmatch_bytes` over the whole input,
+/// as long as no match is longer than `max_match_len`. As with `gmatch_bytes`,
+/// the search continues after each match as if the input started there,
+/// which also happens when the window moves on; so `^` and `%f` are not
+/// meaningful here. Likewise `$` matches at the end of every buffered window,
+/// not just the end of the input, so a match longer than `max_match_len`
+/// may be found there before the rest of the input is read.
+///
+/// ```
+/// use lua_patterns2::{LuaPattern, StreamMatcher};
+/// use std::io::BufReader;
+///
+/// let data: &[u8] = b"abcdefgh";
+/// let reader = BufReader::with_capacity(4, data);
+/// let m = StreamMatcher::new(LuaPattern::new("%a+$"), reader).max_match_len(2);
+/// let ends: Vec<_> = m.map(|res| res.unwrap().range()).collect();
+/// assert_eq!(ends, &[0..4, 4..8]);
+/// ```
+pub struct StreamMatcher<'a, R> {
+	m: LuaPattern<'a>,
+	reader: R,
+	buf: Vec<u8>,
+	// where the next search starts in `buf`
+	pos: usize,
+	// position of `buf[0]` in the stream
+	offset: u64,
+	max_len: usize,
+	// which captures are position captures
+	positions: Vec<bool>,
+	eof: bool
+}
+
+impl <'a, R: BufRead> StreamMatcher<'a, R> {
+	/// Create a matcher reading from `reader`
+	pub fn new(m: LuaPattern<'a>, reader: R) -> StreamMatcher<'a, R> {
+		// captures are numbered from 1 in the order they open
+		let mut positions = std::vec![false];
+		for item in Items::new(m.patt) {
+			match item {
+				Item::Open => positions.push(false),
+				Item::Position => positions.push(true),
+				_ => {}
+			}
+		}
+		StreamMatcher{
+			m,
+			reader,
+			buf: Vec::new(),
+			pos: 0,
+			offset: 0,
+			max_len: DEFAULT_MAX_MATCH_LEN,
+			positions,
+			eof: false
+		}
+	}
+
+	/// Set the longest match expected, which bounds the memory used
+	///
+	/// Longer matches may still be found, but can be cut short
+	/// or missed if they straddle a read.
+	pub fn max_match_len(mut self, len: usize) -> Self {
+		self.max_len = len;
+		self
+	}
+
+	// forget what has been searched and append the next chunk
+	fn fill(&mut self) -> io::Result<()> {
+		self.buf.drain(..self.pos);
+		self.offset += self.pos as u64;
+		self.pos = 0;
+		let chunk = self.reader.fill_buf()?;
+		let n = chunk.len();
+		if n == 0 {
+			self.eof = true;
+		} else {
+			self.buf.extend_from_slice(chunk);
+			self.reader.consume(n);
+		}
+		Ok(())
+	}
+
+	fn next_match(&mut self) -> io::Result<Option<StreamMatch>> {
+		loop {
+			// any match starting at `pos` must now be in the window
+			while ! self.eof && self.buf.len() - self.pos <= self.max_len {
+				self.fill()?;
+			}
+			if self.eof && self.pos >= self.buf.len() {
+				return Ok(None);
+			}
+			let window = &self.buf[self.pos..];
+			// starts from here on might still match with more input
+			let undecided = window.len().saturating_sub(self.max_len);
+			let found = self.m.matches_bytes(window);
+			if found && (self.eof || self.m.range().start < undecided) {
+				let all = self.m.range();
+				let res = StreamMatch{
+					offset: (self.offset + self.pos as u64) + all.start as u64,
+					bytes: window[all.clone()].to_vec(),
+					captures: (0..self.m.n_match).map(|i| {
+						let r = self.m.capture(i);
+						// the engine puts a position capture one before where it is
+						let (start, end) = if self.positions[i] {
+							(r.start.wrapping_add(1), r.end.wrapping_add(1))
+						} else {
+							(r.start, r.end)
+						};
+						(start - all.start)..(end - all.start)
+					}).collect()
+				};
+				// never match the same empty string twice
+				self.pos += all.end.max(all.start + 1);
+				return Ok(Some(res));
+			}
+			if self.eof {
+				return Ok(None);
+			}
+			self.pos += undecided;
+		}
+	}
+}
+
+impl <'a, R: BufRead> Iterator for StreamMatcher<'a, R> {
+	type Item = io::Result<StreamMatch>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		self.next_match().transpose()
+	}
+}
@@ -6,9 +6,15 @@
 //! assert_eq!(res,"'2':a '3':b '4':c ");
 //! ```
 
+use core::borrow::Borrow;
+use core::hash::{BuildHasher, Hash};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
 use std::vec::Vec;
-use std::string::{String, ToString};
-use crate::{LuaPattern, Captures};
+use std::string::String;
+use crate::{keep_bytes, keep_str, LuaPattern, Captures};
+use crate::errors::TemplateError;
+use crate::template::{text_str, Template, TemplatePart};
 
 impl <'a> LuaPattern<'a> {
 	/// Globally substitute all matches with a replacement string
@@ -16,88 +22,566 @@ impl <'a> LuaPattern<'a> {
 	/// This string _may_ have capture references ("%0",..). Use "%%"
 	/// to represent "%". Plain strings like "" work just fine ;)
 	///
+	/// As in Lua, the character after an empty match is kept before
+	/// looking for the next one.
+	///
 	/// ```
 	/// let mut m = lua_patterns2::LuaPattern::new("(%S+)%s*=%s*(%S+);%s*");
 	/// let res = m.gsub("a=2; b=3; c = 4;", "'%2':%1 ");
 	/// assert_eq!(res,"'2':a '3':b '4':c ");
+	/// assert_eq!(lua_patterns2::LuaPattern::new("x*").gsub("abc", "-"), "-a-b-c-");
 	/// ```
 	pub fn gsub(&mut self, text: &str, repl: &str) -> String {
-		let repl = generate_gsub_patterns(repl);
-		let mut slice = text;
+		self.gsub_n(text, repl, usize::MAX).0
+	}
+
+	/// Globally substitute all matches with a replacement string,
+	/// borrowing the text if nothing matches
+	///
+	/// ```
+	/// use std::borrow::Cow;
+	/// let mut m = lua_patterns2::LuaPattern::new("[<>&]");
+	/// assert!(matches!(m.gsub_cow("safe text", "?"), Cow::Borrowed("safe text")));
+	/// assert_eq!(m.gsub_cow("a<b", "?"), "a?b");
+	/// ```
+	pub fn gsub_cow<'t>(&mut self, text: &'t str, repl: &str) -> Cow<'t, str> {
 		let mut res = String::new();
-		while self.matches(slice) {
-			let all = self.range();
-			res.push_str(&slice[0..all.start]);
-			let captures = Captures{m: self, text: slice};
+		let (n, rest) = self.gsub_expanded(text, &Substitute::new(repl), usize::MAX, &mut res);
+		if n == 0 {
+			return Cow::Borrowed(text);
+		}
+		res.push_str(&text[rest..]);
+		Cow::Owned(res)
+	}
+
+	/// Substitute at most `max` matches with a replacement string, also
+	/// returning the number of substitutions, like Lua's `string.gsub`
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("%d%d%d%d");
+	/// let (res, n) = m.gsub_n("pin 1234, then 5678", "****", 1);
+	/// assert_eq!(res, "pin ****, then 5678");
+	/// assert_eq!(n, 1);
+	/// assert_eq!(m.gsub_n("pin 1234, then 5678", "****", 5).1, 2);
+	/// ```
+	pub fn gsub_n(&mut self, text: &str, repl: &str, max: usize) -> (String, usize) {
+		self.gsub_parsed(text, &Substitute::new(repl), max)
+	}
+
+	/// Globally substitute all matches with a replacement string, checking
+	/// it first
+	///
+	/// As in Lua, `%` must be followed by `%` or a capture index, and
+	/// `%1` means the whole match if the pattern has no captures.
+	///
+	/// ```
+	/// use lua_patterns2::errors::TemplateError;
+	/// let mut m = lua_patterns2::LuaPattern::new("(%a+)=(%d+)");
+	/// assert_eq!(m.try_gsub("a=1 b=2", "%2=%1").unwrap(), "1=a 2=b");
+	/// assert_eq!(m.try_gsub("a=1", "%1 is %3"), Err(TemplateError::InvalidCaptureIndex(6, 3)));
+	/// assert_eq!(m.try_gsub("a=1", "100%"), Err(TemplateError::InvalidEscape(3)));
+	///
+	/// let mut m = lua_patterns2::LuaPattern::new("%d+");
+	/// assert_eq!(m.try_gsub("1 22", "<%1>").unwrap(), "<1> <22>");
+	/// ```
+	pub fn try_gsub(&mut self, text: &str, repl: &str) -> Result<String, TemplateError> {
+		let repl = Substitute{
+			repl: parse_template(repl.as_bytes(), Some(self.info.captures), true)?
+		};
+		Ok(self.gsub_parsed(text, &repl, usize::MAX).0)
+	}
+
+	/// Globally substitute all _byte_ matches with a replacement template
+	///
+	/// The template is read as for `gsub`.
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(b"\x01(.)(.)");
+	/// let res = m.gsub_bytes(&[0xAA,0x01,0x02,0x03,0xBB], b"%2%1%%");
+	/// assert_eq!(res, &[0xAA,0x03,0x02,b'%',0xBB]);
+	/// ```
+	pub fn gsub_bytes(&mut self, bytes: &[u8], repl: &[u8]) -> Vec<u8> {
+		self.gsub_bytes_n(bytes, repl, usize::MAX).0
+	}
+
+	/// Globally substitute all _byte_ matches with a replacement template,
+	/// borrowing the bytes if nothing matches
+	pub fn gsub_bytes_cow<'t>(&mut self, bytes: &'t [u8], repl: &[u8]) -> Cow<'t, [u8]> {
+		let mut res = Vec::new();
+		let (n, rest) = self.gsub_bytes_expanded(bytes, repl, usize::MAX, &mut res);
+		if n == 0 {
+			return Cow::Borrowed(bytes);
+		}
+		res.extend_from_slice(&bytes[rest..]);
+		Cow::Owned(res)
+	}
+
+	/// Substitute at most `max` _byte_ matches with a replacement template,
+	/// also returning the number of substitutions
+	pub fn gsub_bytes_n(&mut self, bytes: &[u8], repl: &[u8], max: usize) -> (Vec<u8>, usize) {
+		let mut res = Vec::new();
+		let (n, rest) = self.gsub_bytes_expanded(bytes, repl, max, &mut res);
+		res.extend_from_slice(&bytes[rest..]);
+		(res, n)
+	}
+
+	// the replacements from gsub_bytes_n, and where the rest of `bytes` starts
+	fn gsub_bytes_expanded(&mut self, bytes: &[u8], repl: &[u8], max: usize, res: &mut Vec<u8>) -> (usize, usize) {
+		let repl = generate_gsub_patterns_bytes(repl);
+		let (n, Ok(rest)) = self.gsub_driver(bytes, false, max, res, keep_bytes(bytes), |res, m, start, _| {
+			let slice = &bytes[start..];
 			for r in &repl {
 				match *r {
-					Subst::Text(ref s) => res.push_str(&s),
-					Subst::Capture(i) => res.push_str(captures.get(i))
+					Subst::Text(ref s) => res.extend_from_slice(s),
+					Subst::Capture(i) => res.extend_from_slice(&slice[m.capture(i)])
 				}
 			}
-			slice = &slice[all.end..];
-		}
-		res.push_str(slice);
+			Ok(())
+		});
+		(n, rest)
+	}
+
+	/// Globally substitute matches with values looked up in a table
+	///
+	/// The key is the first capture, or the whole match if there are no
+	/// captures. As in Lua, a match whose key is not found is kept.
+	///
+	/// ```
+	/// use std::collections::HashMap;
+	/// let mut map = HashMap::new();
+	/// map.insert("dolly", "baby");
+	/// map.insert("fine", "cool");
+	/// let mut m = lua_patterns2::LuaPattern::new("%$%((.-)%)");
+	/// let res = m.gsub_map("hello $(dolly) you're so $(fine) and $(good-looking)", &map);
+	/// assert_eq!(res, "hello baby you're so cool and $(good-looking)");
+	///
+	/// let mut m = lua_patterns2::LuaPattern::new("%a+");
+	/// assert_eq!(m.gsub_map("one two three", &[("one", "1"), ("three", "3")]), "1 two 3");
+	/// ```
+	pub fn gsub_map<L: Lookup + ?Sized>(&mut self, text: &str, table: &L) -> String {
+		let mut res = String::new();
+		let (_, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, usize::MAX, &mut res, keep_str(text), |res, m, start, _| {
+			let slice = &text[start..];
+			match table.lookup(&slice[m.first_capture()]) {
+				Some(repl) => res.push_str(&repl),
+				None => res.push_str(&slice[m.range()])
+			}
+			Ok(())
+		});
+		res.push_str(&text[rest..]);
 		res
 	}
+
+	/// Globally substitute all matches with a replacement string in the
+	/// extended syntax of `Substitute::extended`
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::new("(%w+):%s*(%w+)");
+	/// let res = m.gsub_extended("host: example", "%{1:upper:pad6}%2").unwrap();
+	/// assert_eq!(res, "HOST  example");
+	/// assert!(m.gsub_extended("host: example", "%U3").is_err());
+	/// ```
+	pub fn gsub_extended(&mut self, text: &str, repl: &str) -> Result<String, TemplateError> {
+		let repl = Substitute{
+			repl: parse_extended(repl, Some(self.info.captures))?
+		};
+		Ok(self.gsub_parsed(text, &repl, usize::MAX).0)
+	}
+
+	/// Globally substitute all matches with a template parsed beforehand
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// let swap = Substitute::new("%2=%1");
+	/// let mut m = LuaPattern::new("(%w+)=(%w+)");
+	/// assert_eq!(m.gsub_template("a=1 b=2", &swap), "1=a 2=b");
+	/// assert_eq!(m.gsub_template("x=y", &swap), "y=x");
+	/// ```
+	pub fn gsub_template(&mut self, text: &str, repl: &Substitute) -> String {
+		self.gsub_parsed(text, repl, usize::MAX).0
+	}
+
+	fn gsub_parsed(&mut self, text: &str, repl: &Substitute, max: usize) -> (String, usize) {
+		let mut res = String::new();
+		let (n, rest) = self.gsub_expanded(text, repl, max, &mut res);
+		res.push_str(&text[rest..]);
+		(res, n)
+	}
+
+	// the replacements from gsub_parsed, and where the rest of `text` starts
+	fn gsub_expanded(&mut self, text: &str, repl: &Substitute, max: usize, res: &mut String) -> (usize, usize) {
+		let (n, Ok(rest)) = self.gsub_driver(text.as_bytes(), true, max, res, keep_str(text), |res, m, start, _| {
+			let captures = Captures{m, text: &text[start..]};
+			repl.expand_with(|i| captures.get(i), res);
+			Ok(())
+		});
+		(n, rest)
+	}
+}
+
+/// A table of replacements for `gsub_map`
+///
+/// This is implemented for maps and slices of pairs keyed by strings, and
+/// for closures taking the key.
+///
+/// ```
+/// let mut m = lua_patterns2::LuaPattern::new("%d+");
+/// let double = |key: &str| key.parse::<u32>().ok().map(|n| (2 * n).to_string());
+/// assert_eq!(m.gsub_map("2 and 21", &double), "4 and 42");
+/// let small = |key: &str| if key.len() == 1 {Some("small")} else {None};
+/// assert_eq!(m.gsub_map("2 and 21", &small), "small and 21");
+/// ```
+pub trait Lookup {
+	/// The replacement for `key`, or `None` to keep the match
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>>;
+}
+
+impl <K, V, S> Lookup for HashMap<K, V, S>
+where K: Borrow<str> + Hash + Eq, V: AsRef<str>, S: BuildHasher {
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>> {
+		self.get(key).map(|v| Cow::Borrowed(v.as_ref()))
+	}
+}
+
+impl <K, V> Lookup for BTreeMap<K, V>
+where K: Borrow<str> + Ord, V: AsRef<str> {
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>> {
+		self.get(key).map(|v| Cow::Borrowed(v.as_ref()))
+	}
+}
+
+impl <K, V> Lookup for [(K, V)]
+where K: AsRef<str>, V: AsRef<str> {
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>> {
+		self.iter().find(|(k, _)| k.as_ref() == key).map(|(_, v)| Cow::Borrowed(v.as_ref()))
+	}
+}
+
+impl <K, V, const N: usize> Lookup for [(K, V); N]
+where K: AsRef<str>, V: AsRef<str> {
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>> {
+		self[..].lookup(key)
+	}
+}
+
+impl <F, R> Lookup for F
+where F: Fn(&str) -> Option<R>, R: Into<Cow<'static, str>> {
+	fn lookup(&self, key: &str) -> Option<Cow<'_, str>> {
+		self(key).map(Into::into)
+	}
+}
+
+// the widest `pad`, so a template can't ask for a huge allocation
+const MAX_PAD: usize = 255;
+
+// a change made to a capture by the extended template syntax
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Directive {
+	// `upper`, or `%U1`
+	Upper,
+	// `lower`, or `%L1`
+	Lower,
+	// `pad5`, adding spaces after the text to make it 5 characters
+	Pad(usize),
+}
+
+impl Directive {
+	fn from_name(name: &str) -> Option<Directive> {
+		match name {
+			"upper" => Some(Directive::Upper),
+			"lower" => Some(Directive::Lower),
+			_ => {
+				let width = name.strip_prefix("pad")?;
+				if width.is_empty() || ! width.bytes().all(|c| c.is_ascii_digit()) {
+					return None;
+				}
+				width.parse().ok().filter(|&w| w <= MAX_PAD).map(Directive::Pad)
+			}
+		}
+	}
+
+	fn apply(self, text: &str) -> String {
+		match self {
+			Directive::Upper => text.to_uppercase(),
+			Directive::Lower => text.to_lowercase(),
+			Directive::Pad(width) => {
+				let mut res = String::from(text);
+				for _ in text.chars().count()..width {
+					res.push(' ');
+				}
+				res
+			}
+		}
+	}
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Subst {
-	Text(String),
-	Capture(usize)
+	Text(Vec<u8>),
+	Capture(usize),
 }
 
-impl Subst {
-	fn new_text(text: &str) -> Subst {
-		Subst::Text(text.to_string())
+pub fn generate_gsub_patterns(repl: &str) -> Vec<Subst> {
+	generate_gsub_patterns_bytes(repl.as_bytes())
+}
+
+/// Parse a byte replacement template, where `%0` to `%9` are captures
+/// and `%%` is `%`
+///
+/// Any other `%` is taken literally.
+///
+/// ```
+/// use lua_patterns2::{generate_gsub_patterns_bytes, Subst};
+/// assert_eq!(generate_gsub_patterns_bytes(b"%1=%%x%"), &[
+///     Subst::Capture(1),
+///     Subst::Text(b"=%".to_vec()),
+///     Subst::Text(b"x%".to_vec()),
+/// ]);
+/// ```
+pub fn generate_gsub_patterns_bytes(repl: &[u8]) -> Vec<Subst> {
+	let parts = parse_template(repl, None, false).expect("only strict parsing fails");
+	parts.into_iter().map(|part| match part {
+		Part::Text(text) => Subst::Text(text),
+		Part::Capture(index, ..) => Subst::Capture(index)
+	}).collect()
+}
+
+// the template as Lua reads it, checking indices against `captures` if known;
+// unless `strict`, a '%' before anything else is literal
+fn parse_template(b: &[u8], captures: Option<usize>, strict: bool) -> Result<Vec<Part>, TemplateError> {
+	let mut parts = Template::new(b);
+	if strict {
+		parts.check()?;
 	}
+	let mut res = Vec::new();
+	let mut pos = parts.position();
+	while let Some(part) = parts.next() {
+		res.push(match part {
+			TemplatePart::Text(text) => Part::Text(text.to_vec()),
+			TemplatePart::Capture(index) => Part::Capture(capture_index(index, captures, pos)?, pos, Vec::new())
+		});
+		pos = parts.position();
+	}
+	Ok(res)
 }
 
-pub fn generate_gsub_patterns(repl: &str) -> Vec<Subst> {
-	let mut m = LuaPattern::new("%%([%%%d])");
+// a capture index in a template, checked against the number of captures if known
+fn capture_index(index: usize, captures: Option<usize>, pos: usize) -> Result<usize, TemplateError> {
+	match captures {
+		// the whole match, as there is no first capture
+		Some(0) if index == 1 => Ok(0),
+		Some(n) if index > n => Err(TemplateError::InvalidCaptureIndex(pos, index)),
+		_ => Ok(index)
+	}
+}
+
+// one part of a template parsed for `Substitute`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+	Text(Vec<u8>),
+	// a capture, where its `%` is in the template, and the directives
+	// changing it, in order
+	Capture(usize, usize, Vec<Directive>),
+}
+
+// the template in the extended syntax, which is strict like Lua
+fn parse_extended(repl: &str, captures: Option<usize>) -> Result<Vec<Part>, TemplateError> {
+	let b = repl.as_bytes();
 	let mut res = Vec::new();
-	let mut slice = repl;
-	while m.matches(slice) {
-		let all = m.range();
-		let before = &slice[0..all.start];
-		if before != "" {
-			res.push(Subst::new_text(before));
+	// text not yet added
+	let mut text = Vec::new();
+	let mut i = 0;
+	while i < b.len() {
+		if b[i] != b'%' {
+			text.push(b[i]);
+			i += 1;
+			continue;
 		}
-		let capture = &slice[m.capture(1)];
-		if capture == "%" { // escaped literal '%'
-			res.push(Subst::new_text("%"));
-		} else { // has to be a digit
-			let index: usize = capture.parse().unwrap();
-			res.push(Subst::Capture(index));
+		let pos = i;
+		let bad = TemplateError::InvalidDirective(pos);
+		let (index, directives, end) = match b.get(i + 1) {
+			Some(b'%') => {
+				text.push(b'%');
+				i += 2;
+				continue;
+			},
+			Some(&d) if d.is_ascii_digit() => ((d - b'0') as usize, Vec::new(), i + 2),
+			Some(&c) if c == b'U' || c == b'L' => match b.get(i + 2) {
+				Some(&d) if d.is_ascii_digit() => {
+					let directive = if c == b'U' {Directive::Upper} else {Directive::Lower};
+					((d - b'0') as usize, std::vec![directive], i + 3)
+				},
+				_ => return Err(bad)
+			},
+			Some(b'{') => {
+				let close = i + b[i..].iter().position(|&c| c == b'}').ok_or(bad)?;
+				let mut fields = repl[i + 2..close].split(':');
+				let index = fields.next().unwrap_or("");
+				if index.is_empty() || ! index.bytes().all(|c| c.is_ascii_digit()) {
+					return Err(bad);
+				}
+				let index = index.parse().map_err(|_| bad)?;
+				if index > 9 {
+					return Err(TemplateError::InvalidCaptureIndex(pos, index));
+				}
+				let directives = fields.map(Directive::from_name).collect::<Option<Vec<_>>>().ok_or(bad)?;
+				(index, directives, close + 1)
+			},
+			_ => return Err(TemplateError::InvalidEscape(pos))
+		};
+		let index = capture_index(index, captures, pos)?;
+		if ! text.is_empty() {
+			res.push(Part::Text(core::mem::take(&mut text)));
 		}
-		slice = &slice[all.end..];
+		res.push(Part::Capture(index, pos, directives));
+		i = end;
+	}
+	if ! text.is_empty() {
+		res.push(Part::Text(text));
 	}
-	res.push(Subst::new_text(slice));
-	res
+	Ok(res)
 }
 
+/// A replacement template parsed once, to be used for many matches
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Substitute {
-	repl: Vec<Subst>
+	repl: Vec<Part>
 }
 
 impl Substitute {
+	/// Parse a replacement template, as `gsub` does
 	pub fn new(repl: &str) -> Substitute {
 		Substitute{
-			repl: generate_gsub_patterns(repl)
+			repl: parse_template(repl.as_bytes(), None, false).expect("only strict parsing fails")
 		}
 	}
 
+	/// Parse a replacement template, rejecting `%` followed by anything
+	/// but `%` or a digit
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// use lua_patterns2::errors::TemplateError;
+	/// let s = Substitute::try_new("%1%%").unwrap();
+	/// let mut m = LuaPattern::new("(%d+)");
+	/// assert!(m.matches("x 42 y"));
+	/// assert_eq!(s.subst(&m, "x 42 y"), "42%");
+	/// assert_eq!(Substitute::try_new("%s").err(), Some(TemplateError::InvalidEscape(0)));
+	/// ```
+	pub fn try_new(repl: &str) -> Result<Substitute, TemplateError> {
+		Ok(Substitute{
+			repl: parse_template(repl.as_bytes(), None, true)?
+		})
+	}
+
+	/// Parse a replacement template in an extended syntax, which can
+	/// change captures as they are substituted
+	///
+	/// `%U1` and `%L1` are the first capture in upper and lower case.
+	/// `%{1}` is the same as `%1`, and `%{1:upper}`, `%{1:lower}` and
+	/// `%{1:pad5}` change it, the last adding spaces to make it at least 5
+	/// characters, up to 255. Directives can be chained, as in `%{1:lower:pad5}`.
+	///
+	/// These are all errors in Lua, so any template Lua accepts means the
+	/// same here; like Lua, `%` before anything else is an error.
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// let s = Substitute::extended("%U1_%L2 %{1:pad4}|").unwrap();
+	/// let mut m = LuaPattern::new("(%a+)-(%a+)");
+	/// assert_eq!(m.gsub_template("Foo-Bar", &s), "FOO_bar Foo |");
+	/// assert!(Substitute::extended("%{1:shout}").is_err());
+	/// ```
+	pub fn extended(repl: &str) -> Result<Substitute, TemplateError> {
+		Ok(Substitute{
+			repl: parse_extended(repl, None)?
+		})
+	}
+
+	/// The replacement for the last match of `patt` in `text`
+	///
+	/// As in Lua, `%1` means the whole match if `patt` has no captures.
+	/// Panics if the template refers to a capture `patt` doesn't have;
+	/// `try_subst` returns an error instead.
 	pub fn subst(&self, patt: &LuaPattern, text: &str) -> String {
+		self.try_subst(patt, text).unwrap_or_else(|e| panic!("{}", e))
+	}
+
+	/// The replacement for the last match of `patt` in `text`, or an
+	/// error if the template refers to a capture `patt` doesn't have
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// use lua_patterns2::errors::TemplateError;
+	/// let s = Substitute::try_new("%2:%7").unwrap();
+	/// let mut m = LuaPattern::new("(%a)(%d)");
+	/// assert!(m.matches("a1"));
+	/// assert_eq!(s.try_subst(&m, "a1"), Err(TemplateError::InvalidCaptureIndex(3, 7)));
+	/// ```
+	pub fn try_subst(&self, patt: &LuaPattern, text: &str) -> Result<String, TemplateError> {
 		let mut res = String::new();
-		let captures = patt.match_captures(text);
+		self.expand_into(&patt.match_captures(text), &mut res)?;
+		Ok(res)
+	}
+
+	/// Add the replacement for a match to the end of `out`, or return an
+	/// error without adding anything if the template refers to a capture
+	/// the pattern doesn't have
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// let s = Substitute::new("<%1>");
+	/// let mut m = LuaPattern::new("(%a+)");
+	/// let mut out = String::from("tag: ");
+	/// assert!(m.matches("  body  "));
+	/// s.expand_into(&m.match_captures("  body  "), &mut out).unwrap();
+	/// assert_eq!(out, "tag: <body>");
+	/// ```
+	pub fn expand_into(&self, captures: &Captures, out: &mut String) -> Result<(), TemplateError> {
+		let n = captures.m.info.captures;
+		for part in &self.repl {
+			if let Part::Capture(index, pos, _) = *part {
+				capture_index(index, Some(n), pos)?;
+			}
+		}
+		// `%0` and `%1` are the only captures left if there are none
+		self.expand_with(|i| captures.get(if n == 0 {0} else {i}), out);
+		Ok(())
+	}
+
+	/// Add a replacement to the end of `out`, getting each capture
+	/// from `get`
+	///
+	/// This suits captures which do not come from `match_captures`,
+	/// such as those from `gmatch_captures`. The indices are not checked,
+	/// so `get` sees whatever the template holds.
+	///
+	/// ```
+	/// use lua_patterns2::{LuaPattern, Substitute};
+	/// let s = Substitute::new("%2:%1");
+	/// let mut m = LuaPattern::new("(%a)(%d)");
+	/// let mut out = String::new();
+	/// for cc in m.gmatch_captures("a1 b2") {
+	///     s.expand_with(|i| cc.get(i), &mut out);
+	///     out.push(' ');
+	/// }
+	/// assert_eq!(out, "1:a 2:b ");
+	/// ```
+	pub fn expand_with<'t, F>(&self, get: F, out: &mut String)
+	where F: Fn(usize) -> &'t str {
 		for r in &self.repl {
 			match *r {
-				Subst::Text(ref s) => res.push_str(&s),
-				Subst::Capture(i) => res.push_str(captures.get(i))
+				Part::Text(ref s) => out.push_str(text_str(s)),
+				Part::Capture(i, _, ref directives) if directives.is_empty() => out.push_str(get(i)),
+				Part::Capture(i, _, ref directives) => {
+					let mut text = String::from(get(i));
+					for directive in directives {
+						text = directive.apply(&text);
+					}
+					out.push_str(&text);
+				}
 			}
 		}
-		res
 	}
 
 }
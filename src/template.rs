@@ -0,0 +1,219 @@
+//! Replacement templates like `"%2=%1"`, read without allocating.
+//!
+//! ```
+//! use lua_patterns2::{Template, TemplatePart};
+//! let parts: Vec<_> = Template::new(b"%1 is 100%%").collect();
+//! assert_eq!(parts, &[
+//!     TemplatePart::Capture(1),
+//!     TemplatePart::Text(b" is 100%"),
+//! ]);
+//! ```
+
+use core::fmt;
+
+use crate::errors::TemplateError;
+use crate::{Captures, LuaPattern};
+
+/// One part of a replacement template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TemplatePart<'t> {
+	/// Text copied as it is, with `%%` already made `%`
+	Text(&'t [u8]),
+	/// `%0` to `%9`
+	Capture(usize),
+}
+
+/// Iterator over the parts of a replacement template
+///
+/// As with `gsub`, a `%` followed by anything but a digit or `%` is
+/// taken literally; `check` finds these.
+#[derive(Debug, Clone)]
+pub struct Template<'t> {
+	template: &'t [u8],
+	pos: usize,
+}
+
+impl <'t> Template<'t> {
+	/// Read a template
+	pub fn new(template: &'t [u8]) -> Template<'t> {
+		Template{template, pos: 0}
+	}
+
+	/// Where the next part starts in the template
+	pub fn position(&self) -> usize {
+		self.pos
+	}
+
+	/// Check the rest of the template the way Lua does, which only
+	/// allows `%` before `%` or a digit
+	///
+	/// ```
+	/// use lua_patterns2::Template;
+	/// use lua_patterns2::errors::TemplateError;
+	/// assert!(Template::new(b"%1%%").check().is_ok());
+	/// assert_eq!(Template::new(b"50% off").check(), Err(TemplateError::InvalidEscape(2)));
+	/// ```
+	pub fn check(&self) -> Result<(), TemplateError> {
+		let b = self.template;
+		let mut i = self.pos;
+		while i < b.len() {
+			if b[i] == b'%' {
+				match b.get(i + 1) {
+					Some(&c) if c == b'%' || c.is_ascii_digit() => i += 1,
+					_ => return Err(TemplateError::InvalidEscape(i))
+				}
+			}
+			i += 1;
+		}
+		Ok(())
+	}
+}
+
+impl <'t> Iterator for Template<'t> {
+	type Item = TemplatePart<'t>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let b = self.template;
+		let start = self.pos;
+		if start >= b.len() {
+			return None;
+		}
+		let mut i = start;
+		while let Some(off) = b[i..].iter().position(|&c| c == b'%') {
+			let pos = i + off;
+			match b.get(pos + 1) {
+				Some(b'%') => {
+					self.pos = pos + 2;
+					return Some(TemplatePart::Text(&b[start..pos + 1]));
+				},
+				Some(&d) if d.is_ascii_digit() => {
+					if pos > start {
+						self.pos = pos;
+						return Some(TemplatePart::Text(&b[start..pos]));
+					}
+					self.pos = pos + 2;
+					return Some(TemplatePart::Capture((d - b'0') as usize));
+				},
+				// a literal '%'
+				_ => i = pos + 1
+			}
+		}
+		self.pos = b.len();
+		Some(TemplatePart::Text(&b[start..]))
+	}
+}
+
+// text from a template which was a str, split at ASCII '%'
+pub(crate) fn text_str(text: &[u8]) -> &str {
+	core::str::from_utf8(text).expect("template text is a str")
+}
+
+/// A replacement template parsed into at most `N` parts, which borrow
+/// the template, so it can be used many times without allocating
+///
+/// ```
+/// use lua_patterns2::{HeaplessSubstitute, LuaPattern};
+/// let swap = HeaplessSubstitute::<4>::new("%2=%1").unwrap();
+/// let mut m = LuaPattern::new("(%w+)=(%w+)");
+/// let mut out: heapless::String<16> = heapless::String::new();
+/// m.gsub_template_into("a=1 b=2", &swap, &mut out).unwrap();
+/// assert_eq!(out, "1=a 2=b");
+/// assert!(HeaplessSubstitute::<2>::new("%2=%1").is_none());
+/// ```
+#[cfg(feature = "heapless")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaplessSubstitute<'t, const N: usize> {
+	parts: heapless::Vec<TemplatePart<'t>, N>,
+}
+
+#[cfg(feature = "heapless")]
+impl <'t, const N: usize> HeaplessSubstitute<'t, N> {
+	/// Parse a replacement template, as `gsub` does, or `None` if it
+	/// has more than `N` parts
+	pub fn new(repl: &'t str) -> Option<HeaplessSubstitute<'t, N>> {
+		let mut parts = heapless::Vec::new();
+		for part in Template::new(repl.as_bytes()) {
+			parts.push(part).ok()?;
+		}
+		Some(HeaplessSubstitute{parts})
+	}
+
+	/// Write the replacement for a match
+	pub fn expand_into<W: fmt::Write>(&self, captures: &Captures, out: &mut W) -> fmt::Result {
+		for part in &self.parts {
+			match *part {
+				TemplatePart::Text(s) => out.write_str(text_str(s))?,
+				TemplatePart::Capture(i) => out.write_str(captures.get(i))?
+			}
+		}
+		Ok(())
+	}
+}
+
+impl <'a> LuaPattern<'a> {
+	/// Globally substitute all matches with a replacement string,
+	/// writing the result out as it goes
+	///
+	/// The template is read as for `gsub`, but nothing is allocated.
+	///
+	/// ```
+	/// use core::fmt::Write;
+	/// let mut m = lua_patterns2::LuaPattern::new("(%w+)=(%w+)");
+	/// let mut out = String::from("> ");
+	/// m.gsub_into("a=1 b=2", "%2=%1", &mut out).unwrap();
+	/// assert_eq!(out, "> 1=a 2=b");
+	/// ```
+	pub fn gsub_into<W: fmt::Write>(&mut self, text: &str, repl: &str, out: &mut W) -> fmt::Result {
+		let (_, rest) = self.gsub_driver(text.as_bytes(), true, usize::MAX, out,
+			|out: &mut W, r| out.write_str(&text[r]),
+			|out, m, start, _| {
+				let captures = Captures{m, text: &text[start..]};
+				for part in Template::new(repl.as_bytes()) {
+					match part {
+						TemplatePart::Text(s) => out.write_str(text_str(s))?,
+						TemplatePart::Capture(i) => out.write_str(captures.get(i))?
+					}
+				}
+				Ok(())
+			}
+		);
+		out.write_str(&text[rest?..])
+	}
+
+	/// Globally substitute all matches with a template parsed beforehand,
+	/// writing the result out as it goes
+	#[cfg(feature = "heapless")]
+	pub fn gsub_template_into<W: fmt::Write, const N: usize>(&mut self, text: &str, repl: &HeaplessSubstitute<N>, out: &mut W) -> fmt::Result {
+		let (_, rest) = self.gsub_driver(text.as_bytes(), true, usize::MAX, out,
+			|out: &mut W, r| out.write_str(&text[r]),
+			|out, m, start, _| repl.expand_into(&Captures{m, text: &text[start..]}, out)
+		);
+		out.write_str(&text[rest?..])
+	}
+
+	/// Globally substitute all _byte_ matches with a replacement template,
+	/// writing the result out as it goes
+	///
+	/// ```
+	/// let mut m = lua_patterns2::LuaPattern::from_bytes(b"\x01(.)");
+	/// let mut out = Vec::new();
+	/// m.gsub_bytes_into(&[0xAA,0x01,0x02,0xBB], b"%1%1", &mut out).unwrap();
+	/// assert_eq!(out, &[0xAA,0x02,0x02,0xBB]);
+	/// ```
+	#[cfg(feature = "std")]
+	pub fn gsub_bytes_into<W: std::io::Write>(&mut self, bytes: &[u8], repl: &[u8], out: &mut W) -> std::io::Result<()> {
+		let (_, rest) = self.gsub_driver(bytes, false, usize::MAX, out,
+			|out: &mut W, r| out.write_all(&bytes[r]),
+			|out, m, start, _| {
+				for part in Template::new(repl) {
+					match part {
+						TemplatePart::Text(s) => out.write_all(s)?,
+						TemplatePart::Capture(i) => out.write_all(&bytes[start..][m.capture(i)])?
+					}
+				}
+				Ok(())
+			}
+		);
+		out.write_all(&bytes[rest?..])
+	}
+}